@@ -0,0 +1,50 @@
+/// A WebSocket echo server that also answers plain HTTP requests on the same port, which is
+/// useful for load balancer health checks or serving a small demo page.
+
+extern crate ws;
+extern crate env_logger;
+
+use ws::{listen, Handler, Sender, Result, Message, Request, Response};
+
+static INDEX: &'static [u8] = b"<!DOCTYPE html>
+<html>
+    <body>
+        <script>
+            var socket = new WebSocket('ws://' + window.location.host);
+            socket.onmessage = function (event) { document.body.innerText = event.data; };
+            socket.onopen = function () { socket.send('Hello WebSocket'); };
+        </script>
+    </body>
+</html>";
+
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+
+    fn on_http_request(&mut self, req: &Request) -> Result<Response> {
+        match req.resource() {
+            "/" => {
+                let mut res = Response::new(200, "OK", INDEX.to_vec());
+                res.headers_mut().push(("Content-Type".into(), "text/html".into()));
+                Ok(res)
+            }
+            "/health" => Ok(Response::new(200, "OK", b"OK".to_vec())),
+            _ => Ok(Response::new(404, "Not Found", Vec::new())),
+        }
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+}
+
+fn main () {
+
+    // Setup logging
+    env_logger::init().unwrap();
+
+    // Visit http://127.0.0.1:3012 in a browser to open a WebSocket from the demo page
+    listen("127.0.0.1:3012", |out| Server { out: out }).unwrap()
+}
@@ -0,0 +1,51 @@
+/// A benchmark of masking client frames as they are formatted, compared with copying and masking
+/// the payload one byte at a time. Run it in release mode:
+///
+/// cargo run --release --example mask-bench
+
+extern crate ws;
+extern crate time;
+
+use ws::{Frame, OpCode};
+
+const SIZES: [usize; 4] = [125, 4096, 65536, 1 << 20];
+const BYTES: usize = 1 << 30; // masked per size and routine
+
+fn mask_bytes(frame: &mut Frame, buf: &mut Vec<u8>) {
+    let key = [0x37, 0xfa, 0x21, 0x3d];
+    buf.extend(frame.payload().iter().zip(key.iter().cycle()).map(|(&byte, &key)| byte ^ key));
+}
+
+fn mask_frame(frame: &mut Frame, buf: &mut Vec<u8>) {
+    // formatting a frame consumes its mask, so set a new one each time like a client does
+    frame.set_mask().format(buf).unwrap();
+}
+
+fn bench<F>(name: &str, size: usize, mask: F) -> u8
+    where F: Fn(&mut Frame, &mut Vec<u8>)
+{
+    let mut frame = Frame::message(vec![0u8; size], OpCode::Binary, true);
+    let mut buf = Vec::with_capacity(size + 14);
+    let rounds = BYTES / size;
+
+    let start = time::precise_time_ns();
+    for _ in 0..rounds {
+        buf.clear();
+        mask(&mut frame, &mut buf);
+    }
+    let elapsed = time::precise_time_ns() - start;
+
+    let throughput = (rounds * size) as f64 / (elapsed as f64 / 1e9) / (1 << 20) as f64;
+    println!("{:>10} {:>8} bytes: {:>10.1} MB/s", name, size, throughput);
+    // use the result so the loop is not optimized away
+    buf[buf.len() - 1]
+}
+
+fn main() {
+    let mut check = 0;
+    for &size in SIZES.iter() {
+        check ^= bench("bytewise", size, mask_bytes);
+        check ^= bench("ws", size, mask_frame);
+    }
+    println!("(check {})", check);
+}
@@ -1,22 +1,37 @@
 use std::convert::Into;
 use std::borrow::Cow;
+use std::str::from_utf8;
 
 use url;
 use mio;
 use mio::Token;
+#[cfg(feature="serde")]
+use serde::Serialize;
+#[cfg(feature="serde")]
+use serde_json;
 
 use message;
-use result::{Result, Error};
+use frame::Frame;
+use payload::Payload;
+use protocol::OpCode;
+use handshake::ConnectOptions;
+use result::{Result, Error, Kind};
 use protocol::CloseCode;
 use io::ALL;
+#[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+use tls::TlsServerConfig;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Signal {
     Message(message::Message),
+    Shared(OpCode, Payload),
+    Frame(Frame),
     Close(CloseCode, Cow<'static, str>),
     Ping(Vec<u8>),
     Pong(Vec<u8>),
-    Connect(url::Url),
+    Connect(url::Url, ConnectOptions),
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    Tls(TlsServerConfig),
     Shutdown,
     // Stats
 }
@@ -73,6 +88,58 @@ impl Sender {
         }).map_err(Error::from)
     }
 
+    /// Send a message whose payload may be shared with other connections, so sending the same
+    /// payload to many connections does not copy it for each of them. The opcode must be Text,
+    /// in which case the payload must be valid UTF-8, or Binary.
+    #[inline]
+    pub fn send_shared(&self, data: Payload, opcode: OpCode) -> Result<()> {
+        match opcode {
+            OpCode::Text => {
+                try!(from_utf8(&data));
+            }
+            OpCode::Binary => (),
+            _ => return Err(Error::new(Kind::Protocol, "Shared messages must be Text or Binary.")),
+        }
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::Shared(opcode, data),
+        }).map_err(Error::from)
+    }
+
+    /// Serialize a value as JSON and send it over the connection as a text message.
+    #[cfg(feature="serde")]
+    pub fn send_json<T>(&self, value: &T) -> Result<()>
+        where T: Serialize
+    {
+        let text = try!(serde_json::to_string(value).map_err(|err| {
+            Error::new(Kind::Custom(Box::new(err)), "Unable to encode message as JSON.")
+        }));
+        self.send(text)
+    }
+
+    /// Send a single frame over the connection.
+    ///
+    /// This allows for custom fragmentation, reserved bits for an extension or an unusual final
+    /// flag. The frame is passed to `Handler::on_send_frame` like every other outgoing frame. Control
+    /// frames may be sent at any time, but data frames must still form whole messages: a Text or
+    /// Binary frame followed by Continue frames, the last of which is final. Sending a frame that
+    /// breaks this order is a Protocol error on the connection.
+    #[inline]
+    pub fn send_frame(&self, frame: Frame) -> Result<()> {
+        if frame.opcode() == OpCode::Bad {
+            return Err(Error::new(Kind::Protocol, "Attempted to send a frame with an invalid opcode."))
+        }
+        if frame.is_control() && (!frame.is_final() || frame.payload_shared().len() > 125) {
+            return Err(Error::new(
+                Kind::Protocol,
+                "Control frames must be final and have a payload of at most 125 bytes."))
+        }
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::Frame(frame),
+        }).map_err(Error::from)
+    }
+
     /// Send a message to the endpoints of all connections.
     ///
     /// Be careful with this method because it
@@ -133,9 +200,29 @@ impl Sender {
     /// Queue a new connection on this WebSocket to the specified URL.
     #[inline]
     pub fn connect(&self, url: url::Url) -> Result<()> {
+        self.connect_with(url, ConnectOptions::new())
+    }
+
+    /// Queue a new connection on this WebSocket to the specified URL, using the options to
+    /// add headers such as credentials to the handshake request.
+    #[inline]
+    pub fn connect_with(&self, url: url::Url, options: ConnectOptions) -> Result<()> {
+        self.channel.send(Command {
+            token: self.token,
+            signal: Signal::Connect(url, options),
+        }).map_err(Error::from)
+    }
+
+    /// Replace the TLS configuration used to encrypt new server connections, for example after
+    /// a certificate has been renewed. Connections that are already open keep using the
+    /// configuration they were accepted with. This may be called from any Sender, such as the
+    /// one returned by `WebSocket::broadcaster`.
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    #[inline]
+    pub fn reload_tls(&self, config: TlsServerConfig) -> Result<()> {
         self.channel.send(Command {
             token: self.token,
-            signal: Signal::Connect(url),
+            signal: Signal::Tls(config),
         }).map_err(Error::from)
     }
 
@@ -1,30 +1,90 @@
 use std::mem::replace;
+use std::cmp::min;
 use std::mem::transmute;
 use std::borrow::Borrow;
-use std::io::{Write, Read, Cursor, Seek, SeekFrom};
+use std::io;
+use std::io::{Write, Read, Cursor};
 use std::net::SocketAddr;
 use std::collections::VecDeque;
+use std::sync::Arc;
 use std::str::from_utf8;
 
 use url;
 use mio::{Token, TryRead, TryWrite, EventSet};
 use mio::tcp::TcpStream;
-#[cfg(all(not(windows), feature="ssl"))]
+#[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
 use openssl::ssl::NonblockingSslStream;
 
 use message::Message;
-use handshake::{Handshake, Request, Response};
+use handshake::{Handshake, Request, Response, ConnectOptions, extension_name, request_length};
 use frame::Frame;
+use payload::Payload;
+use output::Output;
+use input::Input;
+use utf8::Validator;
 use protocol::{CloseCode, OpCode};
 use result::{Result, Error, Kind};
 use handler::Handler;
 use stream::Stream;
+use origin::OriginPolicy;
+#[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+use tls::{self, TlsServerConfig};
 
 use self::State::*;
 use self::Endpoint::*;
 
 use super::Settings;
 
+/// Split a message into frames of at most `fragment_size` bytes. The frames are slices of the
+/// payload, so the data is not copied.
+pub fn message_frames(opcode: OpCode, data: Payload, fragment_size: usize) -> Vec<Frame> {
+    if data.len() <= fragment_size {
+        debug!("Sending unfragmented message frame.");
+        // true means that the message is done
+        return vec![Frame::shared(data, opcode, true)]
+    }
+
+    debug!("Chunking at {:?}.", fragment_size);
+    let mut frames = Vec::with_capacity(data.len() / fragment_size + 1);
+    let mut start = 0;
+    while start < data.len() {
+        let end = min(start + fragment_size, data.len());
+        let code = if start == 0 { opcode } else { OpCode::Continue };
+        frames.push(Frame::shared(data.slice(start, end), code, end == data.len()));
+        start = end;
+    }
+    frames
+}
+
+// The content type of a TLS record carrying a handshake message, such as the ClientHello.
+#[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+const TLS_HANDSHAKE: u8 = 0x16;
+
+// Look at the next byte from the socket without consuming it.
+#[cfg(all(unix, any(all(not(windows), feature="ssl"), feature="rustls")))]
+fn peek(sock: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    use std::net;
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+
+    let sock = unsafe { net::TcpStream::from_raw_fd(sock.as_raw_fd()) };
+    let res = sock.peek(buf);
+    // the socket still belongs to mio
+    sock.into_raw_fd();
+    res
+}
+
+#[cfg(all(not(unix), feature="rustls"))]
+fn peek(_: &TcpStream, _: &mut [u8]) -> io::Result<usize> {
+    Err(io::Error::new(io::ErrorKind::Other, "Detecting TLS connections is only supported on unix."))
+}
+
+// Check whether a Connection header asks for the connection to be closed.
+fn wants_close(header: Option<&Vec<u8>>) -> bool {
+    header.and_then(|val| from_utf8(val).ok())
+          .map(|val| val.split(',').any(|token| token.trim().to_lowercase() == "close"))
+          .unwrap_or(false)
+}
+
 #[derive(Debug)]
 pub enum State {
     // Tcp connection accepted, waiting for handshake to complete
@@ -82,21 +142,39 @@ pub struct Connection<H>
     events: EventSet,
 
     fragments: VecDeque<Frame>,
+    utf8: Validator,
+    // whether a fragmented message is part of the way through being sent
+    sending_fragments: bool,
 
-    in_buffer: Cursor<Vec<u8>>,
-    out_buffer: Cursor<Vec<u8>>,
+    in_buffer: Input,
+    out_buffer: Output,
 
     handler: H,
 
     addresses: Vec<SocketAddr>,
 
     settings: Settings,
+    origins: Arc<OriginPolicy>,
+    protocols: Arc<Vec<String>>,
+    // extensions that a server must agree to when this is a client
+    required: Vec<String>,
+    // the url and TLS context of a client, needed to set up TLS again when trying the next address
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    url: Option<url::Url>,
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    client_tls: Option<(Arc<tls::ClientContext>, bool)>,
+    // whether to look for a TLS ClientHello before reading the handshake, and the configuration
+    // to encrypt with if there is one
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    detect_tls: bool,
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    server_tls: Option<TlsServerConfig>,
 }
 
 impl<H> Connection<H>
     where H: Handler
 {
-    pub fn new(tok: Token, sock: TcpStream, handler: H, settings: Settings) -> Connection<H> {
+    pub fn new(tok: Token, sock: TcpStream, handler: H, settings: Settings, origins: Arc<OriginPolicy>, protocols: Arc<Vec<String>>) -> Connection<H> {
         Connection {
             token: tok,
             socket: Stream::tcp(sock),
@@ -107,11 +185,24 @@ impl<H> Connection<H>
             endpoint: Endpoint::Server,
             events: EventSet::hup(),
             fragments: VecDeque::with_capacity(settings.fragments_capacity),
-            in_buffer: Cursor::new(Vec::with_capacity(settings.in_buffer_capacity)),
-            out_buffer: Cursor::new(Vec::with_capacity(settings.out_buffer_capacity)),
+            utf8: Validator::new(),
+            sending_fragments: false,
+            in_buffer: Input::new(settings.in_buffer_capacity, settings.in_buffer_grow),
+            out_buffer: Output::new(),
             handler: handler,
             addresses: Vec::new(),
             settings: settings,
+            origins: origins,
+            protocols: protocols,
+            required: Vec::new(),
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            url: None,
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            client_tls: None,
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            detect_tls: false,
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            server_tls: None,
         }
     }
 
@@ -119,12 +210,17 @@ impl<H> Connection<H>
         Ok(self.events.insert(EventSet::readable()))
     }
 
-    pub fn as_client(&mut self, url: &url::Url, addrs: Vec<SocketAddr>) -> Result<()> {
+    pub fn as_client(&mut self, url: &url::Url, addrs: Vec<SocketAddr>, options: &ConnectOptions) -> Result<()> {
         if let Connecting(ref mut req, _) = self.state {
             self.addresses = addrs;
+            self.required = options.required_extensions().clone();
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            { self.url = Some(url.clone()); }
             self.events.insert(EventSet::writable());
             self.endpoint = Endpoint::Client;
-            try!(self.handler.build_request(url)).format(req.get_mut())
+            let mut request = try!(self.handler.build_request(url));
+            options.apply(&mut request);
+            request.format(req.get_mut())
         } else {
             Err(Error::new(
                 Kind::Internal,
@@ -132,21 +228,101 @@ impl<H> Connection<H>
         }
     }
 
-    #[cfg(all(not(windows), feature="ssl"))]
-    pub fn encrypt(&mut self) -> Result<()> {
+    // Wrap the socket in TLS, using the Ssl object from a shared configuration if there is one
+    // and otherwise building one with the handler.
+    #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
+    pub fn encrypt(&mut self, ssl: Option<tls::Session>) -> Result<()> {
+        let ssl = match ssl {
+            Some(ssl) => ssl,
+            None => match try!(self.handler_ssl()) {
+                Some(ssl) => ssl,
+                None => try!(tls::default_server_ssl()),
+            },
+        };
+        let sock = try!(self.take_socket());
         let ssl_stream = match self.endpoint {
-            Server => try!(NonblockingSslStream::accept(
-                try!(self.handler.build_ssl()),
-                try!(self.socket().try_clone()))),
-
-            Client => try!(NonblockingSslStream::connect(
-                try!(self.handler.build_ssl()),
-                try!(self.socket().try_clone()))),
+            Server => try!(NonblockingSslStream::accept(ssl, sock)),
+            Client => try!(NonblockingSslStream::connect(ssl, sock)),
         };
 
         Ok(self.socket = Stream::tls(ssl_stream))
     }
 
+    // Wrap the socket in a rustls session. Unlike openssl there is no handler fallback, so the
+    // session must come from the client url or the server configuration.
+    #[cfg(feature="rustls")]
+    pub fn encrypt(&mut self, session: Option<tls::Session>) -> Result<()> {
+        let session = try!(session.ok_or(
+            Error::new(Kind::Internal, "Unable to encrypt the connection without a TLS session.")));
+        let sock = try!(self.take_socket());
+        Ok(self.socket = Stream::rustls(sock, session))
+    }
+
+    // Take the socket out of a plain connection without closing it, so that the descriptor that
+    // is registered with the event loop stays the same once the connection is encrypted.
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    fn take_socket(&mut self) -> Result<TcpStream> {
+        let placeholder = Stream::tcp(try!(self.socket().try_clone()));
+        replace(&mut self.socket, placeholder).into_tcp().ok_or(
+            Error::new(Kind::Internal, "Tried to encrypt a connection that is already encrypted."))
+    }
+
+    // Wait for the first bytes from the client to decide whether to encrypt the connection.
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    pub fn detect_tls(&mut self, config: Option<TlsServerConfig>) {
+        self.detect_tls = true;
+        self.server_tls = config;
+    }
+
+    // Encrypt the connection if the client starts with a TLS handshake. Returns false if there is
+    // nothing to look at yet.
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    fn check_tls(&mut self) -> Result<bool> {
+        if !self.detect_tls {
+            return Ok(true)
+        }
+        let mut first = [0u8; 1];
+        match peek(self.socket(), &mut first) {
+            Ok(len) => {
+                self.detect_tls = false;
+                if len > 0 && first[0] == TLS_HANDSHAKE {
+                    debug!("Detected TLS handshake from {}.", try!(self.socket.peer_addr()));
+                    let session = try!(tls::server_session(&self.server_tls));
+                    try!(self.encrypt(session));
+                }
+                Ok(true)
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    // The Ssl object built by the handler, if any.
+    #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
+    fn handler_ssl(&mut self) -> Result<Option<tls::Session>> {
+        self.handler.build_ssl()
+    }
+
+    #[cfg(feature="rustls")]
+    fn handler_ssl(&mut self) -> Result<Option<tls::Session>> {
+        Ok(None)
+    }
+
+    // Wrap the socket of a client in TLS, verifying the server against the host of the url.
+    // Unless the context comes from a configuration given to the Builder, a handler that
+    // overrides `build_ssl` provides the Ssl object instead.
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    pub fn encrypt_client(&mut self, context: Arc<tls::ClientContext>, configured: bool) -> Result<()> {
+        let handler_ssl = if configured { None } else { try!(self.handler_ssl()) };
+        let session = match (handler_ssl, self.url.as_ref()) {
+            (Some(ssl), _) => ssl,
+            (None, Some(url)) => try!(tls::client_session(url, &context)),
+            (None, None) => return Err(Error::new(Kind::Internal, "Tried to encrypt a connection that is not a client.")),
+        };
+        self.client_tls = Some((context, configured));
+        self.encrypt(Some(session))
+    }
+
     pub fn token(&self) -> Token {
         self.token
     }
@@ -155,42 +331,21 @@ impl<H> Connection<H>
         &self.socket.evented()
     }
 
-    // Resetting may be necessary in order to try all possible addresses for a server
-    #[cfg(all(not(windows), feature="ssl"))]
-    pub fn reset(&mut self) -> Result<()> {
-        if self.is_client() {
-            if let Connecting(ref mut req, ref mut res) = self.state {
-                req.set_position(0);
-                res.set_position(0);
-                self.events.remove(EventSet::readable());
-                self.events.insert(EventSet::writable());
+    #[cfg(not(any(all(not(windows), feature="ssl"), feature="rustls")))]
+    fn reconnect(&mut self, sock: TcpStream) -> Result<()> {
+        Ok(self.socket = Stream::tcp(sock))
+    }
 
-                if let Some(ref addr) = self.addresses.pop() {
-                    let sock = try!(TcpStream::connect(addr));
-                    if self.socket.is_tls() {
-                        Ok(self.socket = Stream::tls(
-                                try!(NonblockingSslStream::connect(
-                                    try!(self.handler.build_ssl()),
-                                    sock))))
-
-                    } else {
-                        Ok(self.socket = Stream::tcp(sock))
-                    }
-                } else {
-                    if self.settings.panic_on_new_connection {
-                        panic!("Unable to connect to server.");
-                    }
-                    Err(Error::new(Kind::Internal, "Unable to connect to server."))
-                }
-            } else {
-                Err(Error::new(Kind::Internal, "Unable to reset client connection because it is active."))
-            }
-        } else {
-            Err(Error::new(Kind::Internal, "Server connections cannot be reset."))
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    fn reconnect(&mut self, sock: TcpStream) -> Result<()> {
+        self.socket = Stream::tcp(sock);
+        if let Some((context, configured)) = self.client_tls.clone() {
+            try!(self.encrypt_client(context, configured))
         }
+        Ok(())
     }
 
-    #[cfg(not(feature="ssl"))]
+    // Resetting may be necessary in order to try all possible addresses for a server
     pub fn reset(&mut self) -> Result<()> {
         if self.is_client() {
             if let Connecting(ref mut req, ref mut res) = self.state {
@@ -201,7 +356,7 @@ impl<H> Connection<H>
 
                 if let Some(ref addr) = self.addresses.pop() {
                     let sock = try!(TcpStream::connect(addr));
-                    Ok(self.socket = Stream::tcp(sock))
+                    self.reconnect(sock)
                 } else {
                     if self.settings.panic_on_new_connection {
                         panic!("Unable to connect to server.");
@@ -250,7 +405,7 @@ impl<H> Connection<H>
         match self.state {
             Connecting(_, ref mut res) => {
                 match err.kind {
-                    #[cfg(all(not(windows), feature="ssl"))]
+                    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
                     Kind::Ssl(_) | Kind::Io(_) => {
                         self.handler.on_error(err);
                         self.events = EventSet::none();
@@ -368,32 +523,74 @@ impl<H> Connection<H>
     }
 
     fn write_handshake(&mut self) -> Result<()> {
+        let mut close = false;
+        let mut pipelined = false;
         if let Connecting(ref mut req, ref mut res) = self.state {
             match self.endpoint {
                 Server => {
-                    let mut done = false;
-                    if let Some(len) = try!(self.socket.try_write_buf(res)) {
-                        if res.get_ref().len() == len {
-                            done = true
-                        }
-                    }
-                    if !done {
+                    try!(self.socket.try_write_buf(res));
+                    // TLS may still be holding on to part of the response
+                    if res.position() as usize != res.get_ref().len() || self.socket.wants_write() {
                         return Ok(())
                     }
-                }
-                Client =>  {
-                    if let Some(len) = try!(self.socket.try_write_buf(req)) {
-                        if req.get_ref().len() == len {
-                            debug!("Finished writing handshake request to {}", try!(self.socket.peer_addr()));
-                            self.events.insert(EventSet::readable());
+
+                    let response = try!(try!(Response::parse(res.get_ref())).ok_or(
+                        Error::new(Kind::Internal, "Failed to parse response after it was written.")));
+
+                    if response.status() != 101 {
+                        // the response may be the answer to a request that could not be parsed,
+                        // in which case there is no telling where the next request starts
+                        let request = Request::parse(req.get_ref()).ok().and_then(|request| request);
+                        let keep_alive = request.map(|request| {
+                            !request.is_websocket_upgrade() && !wants_close(request.header("connection"))
+                        }).unwrap_or(false) &&
+                            response.header("content-length").is_some() &&
+                            !wants_close(response.header("connection"));
+
+                        if keep_alive {
+                            debug!("Finished writing HTTP response to {}, waiting for next request.", try!(self.socket.peer_addr()));
+                            // keep any request that the client sent without waiting for this response
+                            let len = request_length(req.get_ref()).unwrap_or(req.get_ref().len());
+                            req.get_mut().drain(..len);
+                            req.set_position(0);
+                            res.get_mut().clear();
+                            res.set_position(0);
                             self.events.remove(EventSet::writable());
+                            self.events.insert(EventSet::readable());
+                            if req.get_ref().is_empty() {
+                                return Ok(())
+                            }
+                            pipelined = true;
+                        } else {
+                            close = true;
                         }
                     }
+                }
+                Client =>  {
+                    try!(self.socket.try_write_buf(req));
+                    if req.position() as usize == req.get_ref().len() && !self.socket.wants_write() {
+                        debug!("Finished writing handshake request to {}", try!(self.socket.peer_addr()));
+                        self.events.insert(EventSet::readable());
+                        self.events.remove(EventSet::writable());
+                    }
                     return Ok(())
                 }
             }
         }
 
+        if pipelined {
+            // the next request has already arrived, so there won't be another readable event for it
+            return self.read_handshake()
+        }
+
+        if close {
+            // the response did not switch protocols, so there is nothing more to do
+            debug!("Finished writing HTTP response to {}, disconnecting.", try!(self.socket.peer_addr()));
+            self.state = Closing;
+            self.events = EventSet::none();
+            return Ok(())
+        }
+
         if let Connecting(ref req, ref res) = replace(&mut self.state, Open) {
             debug!("Finished writing handshake response to {}", try!(self.socket.peer_addr()));
             debug!("Connection to {} is now open.", try!(self.socket.peer_addr()));
@@ -418,6 +615,8 @@ impl<H> Connection<H>
                     response: response,
                     peer_addr: self.socket.peer_addr().ok(),
                     local_addr: self.socket.local_addr().ok(),
+                    server_name: self.socket.server_name(),
+                    tls: self.socket.tls_info(),
                 }));
                 self.events.insert(EventSet::readable());
                 return Ok(self.check_events())
@@ -431,10 +630,31 @@ impl<H> Connection<H>
         if let Connecting(ref mut req, ref mut res) = self.state {
             match self.endpoint {
                 Server => {
-                    if let Some(_) = try!(self.socket.try_read_buf(req.get_mut())) {
+                    // a request may already be buffered if it was pipelined after the last one
+                    if try!(self.socket.try_read_buf(req.get_mut())).is_some() || !req.get_ref().is_empty() {
                         if let Some(ref request) = try!(Request::parse(req.get_ref())) {
-                            debug!("Handshake request received: \n{}", request);
-                            let response = try!(self.handler.on_request(request));
+                            let response = if request.is_websocket_upgrade() {
+                                debug!("Handshake request received: \n{}", request);
+                                let origin = try!(request.origin());
+                                if self.origins.allows(origin) {
+                                    let mut response = try!(self.handler.on_request(request));
+                                    if response.status() == 101 && !self.protocols.is_empty() && try!(response.protocol()).is_none() {
+                                        let selected = try!(response.select_protocol(request, &self.protocols[..]));
+                                        if selected.is_none() && self.settings.protocol_strict {
+                                            return Err(Error::new(
+                                                Kind::Protocol,
+                                                format!("Client offered none of the supported subprotocols: {:?}", self.protocols)))
+                                        }
+                                    }
+                                    response
+                                } else {
+                                    info!("Rejecting WebSocket handshake from disallowed origin {:?}.", origin);
+                                    Response::new(403, "Forbidden", Vec::new())
+                                }
+                            } else {
+                                debug!("Plain HTTP request received: \n{}", request);
+                                try!(self.handler.on_http_request(request))
+                            };
                             try!(response.format(res.get_mut()));
                             self.events.remove(EventSet::readable());
                             self.events.insert(EventSet::writable());
@@ -455,7 +675,7 @@ impl<H> Connection<H>
                             if !data[..end].ends_with(b"\r\n\r\n") {
                                 return Ok(())
                             }
-                            self.in_buffer.get_mut().extend(&data[end..]);
+                            self.in_buffer.extend(&data[end..]);
                             end
                         };
                         res.get_mut().truncate(end);
@@ -484,6 +704,28 @@ impl<H> Connection<H>
                 }
             }
 
+            if let Some(proto) = try!(response.protocol()) {
+                if !try!(request.protocols()).contains(&proto) {
+                    return Err(Error::new(Kind::Protocol, format!("Server selected a subprotocol that was not offered: {}", proto)))
+                }
+            }
+
+            // the server may only agree to extensions that we offered, and it must agree to those
+            // that we require
+            let offered = try!(request.extensions()).into_iter().map(extension_name).collect::<Vec<&str>>();
+            let accepted = try!(response.extensions()).into_iter().map(extension_name).filter(|ext| !ext.is_empty()).collect::<Vec<&str>>();
+            if let Some(ext) = accepted.iter().find(|ext| !offered.contains(*ext)) {
+                let reason = format!("Server agreed to an extension that was not offered: {}", ext);
+                self.handler.on_error(Error::new(Kind::Protocol, reason.clone()));
+                return self.send_close(CloseCode::Extension, reason)
+            }
+            let missing = self.required.iter().filter(|ext| !accepted.contains(&&ext[..])).cloned().collect::<Vec<String>>();
+            if !missing.is_empty() {
+                let reason = missing.join(", ");
+                self.handler.on_error(Error::new(Kind::Protocol, format!("Server did not agree to required extensions: {}", reason)));
+                return self.send_close(CloseCode::Extension, reason)
+            }
+
             if self.settings.key_strict {
                 let req_key = try!(request.hashed_key());
                 let res_key = try!(from_utf8(try!(response.key())));
@@ -498,10 +740,12 @@ impl<H> Connection<H>
                     response: response,
                     peer_addr: self.socket.peer_addr().ok(),
                     local_addr: self.socket.local_addr().ok(),
+                    server_name: self.socket.server_name(),
+                    tls: self.socket.tls_info(),
             }));
 
             // check to see if there is anything to read already
-            if !self.in_buffer.get_ref().is_empty() {
+            if !self.in_buffer.is_empty() {
                 try!(self.read_frames());
             }
 
@@ -511,6 +755,13 @@ impl<H> Connection<H>
     }
 
     pub fn read(&mut self) -> Result<()> {
+        #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+        {
+            if !try!(self.check_tls()) {
+                return Ok(())
+            }
+        }
+
         if self.socket.is_negotiating() {
             try!(self.socket.clear_negotiating());
             self.write()
@@ -536,10 +787,10 @@ impl<H> Connection<H>
     }
 
     fn read_frames(&mut self) -> Result<()> {
-        while let Some(mut frame) = try!(Frame::parse(&mut self.in_buffer)) {
+        while let Some((frame, masked)) = try!(self.in_buffer.next_frame()) {
 
             if self.settings.masking_strict {
-                if frame.is_masked() {
+                if masked {
                     if self.is_client() {
                         return Err(Error::new(Kind::Protocol, "Received masked frame from a server endpoint."))
                     }
@@ -550,9 +801,6 @@ impl<H> Connection<H>
                 }
             }
 
-            // This is safe whether or not a frame is masked.
-            frame.remove_mask();
-
             if frame.is_final() {
                 match frame.opcode() {
                     // singleton data frames
@@ -662,7 +910,11 @@ impl<H> Connection<H>
                         debug!("Received final fragment {:?}", frame);
                         if let Some(last) = try!(self.handler.on_frame(frame)) {
                             if let Some(first) = self.fragments.pop_front() {
-                                let size = self.fragments.iter().fold(first.payload().len() + last.payload().len(), |len, frame| len + frame.payload().len());
+                                if first.opcode() == OpCode::Text {
+                                    try!(self.utf8.validate(last.payload_shared()));
+                                    try!(self.utf8.finish());
+                                }
+                                let size = self.fragments.iter().fold(first.payload_shared().len() + last.payload_shared().len(), |len, frame| len + frame.payload_shared().len());
                                 match first.opcode() {
                                     OpCode::Text => {
                                         debug!("Constructing text message from fragments: {:?} -> {:?} -> {:?}", first, self.fragments.iter().collect::<Vec<&Frame>>(), last);
@@ -673,7 +925,10 @@ impl<H> Connection<H>
                                         }
                                         data.extend(last.into_data());
 
-                                        let string = try!(String::from_utf8(data).map_err(|err| err.utf8_error()));
+                                        // SAFETY: every fragment was passed to `self.utf8.validate` as it
+                                        // arrived and `self.utf8.finish` checked that no character was
+                                        // left incomplete, so the joined bytes are valid UTF-8.
+                                        let string = unsafe { String::from_utf8_unchecked(data) };
 
                                         debug!("Calling handler with constructed message: {:?}", string);
                                         try!(self.handler.on_message(Message::text(string)));
@@ -708,6 +963,15 @@ impl<H> Connection<H>
                     OpCode::Text | OpCode::Binary | OpCode::Continue => {
                         debug!("Received non-final fragment frame {:?}", frame);
                         if let Some(frame) = try!(self.handler.on_frame(frame)) {
+                            // validate text as it arrives so that invalid data fails the
+                            // connection without waiting for the rest of the message
+                            let is_text = match self.fragments.front() {
+                                Some(first) => first.opcode() == OpCode::Text,
+                                None => frame.opcode() == OpCode::Text,
+                            };
+                            if is_text {
+                                try!(self.utf8.validate(frame.payload_shared()));
+                            }
                             self.fragments.push_back(frame)
                         }
                     }
@@ -734,10 +998,10 @@ impl<H> Connection<H>
                 // Start out assuming that this write will clear the whole buffer
                 self.events.remove(EventSet::writable());
 
-                while let Some(len) = try!(self.socket.try_write_buf(&mut self.out_buffer)) {
+                while let Some(len) = try!(self.out_buffer.write_to(&mut self.socket)) {
                     debug!("Wrote {} bytes to {}", len, try!(self.socket.peer_addr()));
-                    let finished = len == 0 || self.out_buffer.position() as usize == self.out_buffer.get_ref().len();
-                    if finished && self.is_server() && self.state.is_closing() {
+                    let finished = len == 0 || self.out_buffer.is_empty();
+                    if finished && self.is_server() && self.state.is_closing() && !self.socket.wants_write() {
                         // we are are a server that is closing and just wrote out our last frame,
                         // let's disconnect
                         return Ok(self.events = EventSet::none());
@@ -746,6 +1010,10 @@ impl<H> Connection<H>
                     }
                 }
 
+                if self.out_buffer.is_empty() && !self.socket.wants_write() {
+                    self.handler.on_drain();
+                }
+
                 // Check if there is more to write so that the connection will be rescheduled
                 Ok(self.check_events())
             };
@@ -761,34 +1029,29 @@ impl<H> Connection<H>
     pub fn send_message(&mut self, msg: Message) -> Result<()> {
         let opcode = msg.opcode();
         debug!("Message opcode {:?}", opcode);
-        let data = msg.into_data();
-        if data.len() > self.settings.fragment_size {
-            debug!("Chunking at {:?}.", self.settings.fragment_size);
-            // note this copies the data, so it's actually somewhat expensive to fragment
-            let mut chunks = data.chunks(self.settings.fragment_size).peekable();
-            let chunk = chunks.next().expect("Unable to get initial chunk!");
-
-            try!(self.buffer_frame(
-                Frame::message(Vec::from(chunk), opcode, false)));
-
-            while let Some(chunk) = chunks.next() {
-                if let Some(_) = chunks.peek() {
-                    try!(self.buffer_frame(
-                        Frame::message(Vec::from(chunk), OpCode::Continue, false)));
-                } else {
-                    try!(self.buffer_frame(
-                        Frame::message(Vec::from(chunk), OpCode::Continue, true)));
-                }
-            }
+        self.send_shared(opcode, Payload::new(msg.into_data()))
+    }
 
-        } else {
-            debug!("Sending unfragmented message frame.");
-            // true means that the message is done
-            try!(self.buffer_frame(Frame::message(data, opcode, true)));
+    // Send a message whose payload may be shared with other connections.
+    pub fn send_shared(&mut self, opcode: OpCode, data: Payload) -> Result<()> {
+        let frames = message_frames(opcode, data, self.settings.fragment_size);
+        self.send_frames(frames)
+    }
+
+    // Send the frames of a message, which may be shared with other connections.
+    pub fn send_frames(&mut self, frames: Vec<Frame>) -> Result<()> {
+        for frame in frames {
+            try!(self.buffer_frame(frame));
         }
         Ok(self.check_events())
     }
 
+    pub fn send_frame(&mut self, frame: Frame) -> Result<()> {
+        debug!("Sending raw frame to {}.", try!(self.socket.peer_addr()));
+        try!(self.buffer_frame(frame));
+        Ok(self.check_events())
+    }
+
     #[inline]
     pub fn send_ping(&mut self, data: Vec<u8>) -> Result<()> {
         debug!("Sending ping to {}.", try!(self.socket.peer_addr()));
@@ -821,7 +1084,8 @@ impl<H> Connection<H>
     fn check_events(&mut self) {
         if !self.state.is_connecting() {
             self.events.insert(EventSet::readable());
-            if (self.out_buffer.position() as usize) < self.out_buffer.get_ref().len() {
+            if !self.out_buffer.is_empty() || self.socket.wants_write()
+            {
                 self.events.insert(EventSet::writable());
             }
         }
@@ -829,6 +1093,7 @@ impl<H> Connection<H>
 
     fn buffer_frame(&mut self, frame: Frame) -> Result<()> {
         if let Some(mut frame) = try!(self.handler.on_send_frame(frame)) {
+            try!(self.check_sequence(&frame));
             try!(self.check_buffer_out(&frame));
 
             if self.is_client() {
@@ -837,28 +1102,47 @@ impl<H> Connection<H>
 
             debug!("Buffering frame to {}:\n{}", try!(self.socket.peer_addr()), frame);
 
-            let pos = self.out_buffer.position();
-            try!(self.out_buffer.seek(SeekFrom::End(0)));
-            try!(frame.format(&mut self.out_buffer));
-            try!(self.out_buffer.seek(SeekFrom::Start(pos)));
+            self.out_buffer.push_frame(frame);
         }
         Ok(())
     }
 
-    fn check_buffer_out(&mut self, frame: &Frame) -> Result<()> {
-
-        if self.out_buffer.get_ref().capacity() <= self.out_buffer.get_ref().len() + frame.len() {
-            // extend
-            let mut new = Vec::with_capacity(self.out_buffer.get_ref().capacity());
-            new.extend(&self.out_buffer.get_ref()[self.out_buffer.position() as usize ..]);
-            if new.len() == new.capacity() {
-                if self.settings.out_buffer_grow {
-                    new.reserve(self.settings.out_buffer_capacity)
-                } else {
-                    return Err(Error::new(Kind::Capacity, "Maxed out output buffer for connection."))
+    // Make sure that the data frames being sent form whole messages. Control frames may be sent
+    // between the fragments of a message.
+    fn check_sequence(&mut self, frame: &Frame) -> Result<()> {
+        match frame.opcode() {
+            OpCode::Close | OpCode::Ping | OpCode::Pong => {
+                if !frame.is_final() {
+                    return Err(Error::new(Kind::Protocol, "Attempted to send a fragmented control frame."))
                 }
+                if frame.payload_shared().len() > 125 {
+                    return Err(Error::new(Kind::Protocol, "Attempted to send a control frame longer than 125 bytes."))
+                }
+            }
+            OpCode::Text | OpCode::Binary => {
+                if self.sending_fragments {
+                    return Err(Error::new(Kind::Protocol, "Attempted to start a message before the previous message was finished."))
+                }
+                self.sending_fragments = !frame.is_final();
+            }
+            OpCode::Continue => {
+                if !self.sending_fragments {
+                    return Err(Error::new(Kind::Protocol, "Attempted to send a continuation frame outside of a fragmented message."))
+                }
+                self.sending_fragments = !frame.is_final();
+            }
+            OpCode::Bad => {
+                return Err(Error::new(Kind::Protocol, "Attempted to send a frame with an invalid opcode."))
             }
-            self.out_buffer = Cursor::new(new);
+        }
+        Ok(())
+    }
+
+    fn check_buffer_out(&mut self, frame: &Frame) -> Result<()> {
+        if !self.settings.out_buffer_grow &&
+            self.out_buffer.len() + frame.len() > self.settings.out_buffer_capacity
+        {
+            return Err(Error::new(Kind::Capacity, "Maxed out output buffer for connection."))
         }
         Ok(())
     }
@@ -866,44 +1150,19 @@ impl<H> Connection<H>
     fn buffer_in(&mut self) -> Result<Option<usize>> {
 
         debug!("Reading buffer for connection to {}.", try!(self.socket.peer_addr()));
-        if let Some(mut len) = try!(self.socket.try_read_buf(self.in_buffer.get_mut())) {
-            if len == 0 {
-                debug!("Buffered {}.", len);
-                return Ok(None)
-            }
-            loop {
-                if self.in_buffer.get_ref().len() == self.in_buffer.get_ref().capacity() {
-                    // extend
-                    let mut new = Vec::with_capacity(self.in_buffer.get_ref().capacity());
-                    new.extend(&self.in_buffer.get_ref()[self.in_buffer.position() as usize ..]);
-                    if new.len() == new.capacity() {
-                        if self.settings.in_buffer_grow {
-                            new.reserve(self.settings.in_buffer_capacity);
-                        } else {
-                            return Err(Error::new(Kind::Capacity, "Maxed out input buffer for connection."))
-                        }
-
-                        self.in_buffer = Cursor::new(new);
-                        // return now so that hopefully we will consume some of the buffer so this
-                        // won't happen next time
-                        debug!("Buffered {}.", len);
-                        return Ok(Some(len));
-                    }
-                    self.in_buffer = Cursor::new(new);
-                }
-
-                if let Some(next) = try!(self.socket.try_read_buf(self.in_buffer.get_mut())) {
-                    if next == 0 {
-                        return Ok(Some(len))
-                    }
-                    len += next
-                } else {
-                    debug!("Buffered {}.", len);
-                    return Ok(Some(len))
-                }
+        let mut len = 0;
+        // a read of zero means that the other endpoint has closed the stream
+        while let Some(next) = try!(self.in_buffer.read_from(&mut self.socket)) {
+            if next == 0 {
+                break
             }
-        } else {
+            len += next;
+        }
+        debug!("Buffered {}.", len);
+        if len == 0 {
             Ok(None)
+        } else {
+            Ok(Some(len))
         }
     }
 }
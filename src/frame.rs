@@ -1,22 +1,49 @@
 use std::fmt;
 use std::mem::transmute;
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, Write};
 use std::default::Default;
 use std::iter::FromIterator;
 
 use rand;
-use mio::TryRead;
 
 use result::{Result, Error, Kind};
+use payload::Payload;
 use protocol::{OpCode, CloseCode};
 
-fn apply_mask(buf: &mut [u8], mask: &[u8; 4]) {
+// Mask one byte at a time. This is the reference for `apply_mask`, and handles the parts of the
+// buffer that are not aligned to a word.
+#[inline]
+fn apply_mask_bytes(buf: &mut [u8], mask: &[u8; 4]) {
     let iter = buf.iter_mut().zip(mask.iter().cycle());
     for (byte, &key) in iter {
         *byte ^= key
     }
 }
 
+// Mask or unmask a payload in place.
+//
+// The aligned middle of the buffer is XORed a u64 at a time with the mask repeated twice, after
+// rotating the mask to line up with the bytes before it.
+pub fn apply_mask(buf: &mut [u8], mask: &[u8; 4]) {
+    let (head, words, tail) = unsafe { buf.align_to_mut::<u64>() };
+    apply_mask_bytes(head, mask);
+
+    let shift = head.len() % 4;
+    let rotated = [
+        mask[shift],
+        mask[(shift + 1) % 4],
+        mask[(shift + 2) % 4],
+        mask[(shift + 3) % 4],
+    ];
+    let wide: u64 = unsafe { transmute([rotated, rotated]) };
+    for word in words.iter_mut() {
+        *word ^= wide;
+    }
+
+    // the words cover a multiple of four bytes, so the tail lines up with the rotated mask too
+    apply_mask_bytes(tail, &rotated);
+}
+
 #[inline]
 fn generate_mask() -> [u8; 4] {
     unsafe { transmute(rand::random::<u32>()) }
@@ -33,7 +60,10 @@ pub struct Frame {
 
     mask: Option<[u8; 4]>,
 
-    payload: Vec<u8>,
+    payload: Payload,
+
+    // The formatted frame, which clones of a broadcast frame share until one of them is changed.
+    encoded: Option<Payload>,
 }
 
 impl Frame {
@@ -43,7 +73,7 @@ impl Frame {
     #[inline]
     pub fn len(&self) -> usize {
         let mut header_length = 2;
-        let payload_len = self.payload().len();
+        let payload_len = self.payload.len();
         if payload_len > 125 {
             if payload_len <= u16::max_value() as usize {
                 header_length += 2;
@@ -89,12 +119,29 @@ impl Frame {
         self.opcode
     }
 
-    /// Get a reference to the frame's payload.
+    /// Get a reference to the frame's payload. The payload of a fragment made by slicing a larger
+    /// message is copied the first time this is called, which `payload_shared` avoids.
     #[inline]
     pub fn payload(&self) -> &Vec<u8> {
+        self.payload.as_vec()
+    }
+
+    /// Get a reference to the frame's payload, which may be cloned to share it with other frames.
+    #[inline]
+    pub fn payload_shared(&self) -> &Payload {
         &self.payload
     }
 
+    /// Test whether the frame is a control frame, which may be sent between the fragments of a
+    /// message.
+    #[inline]
+    pub fn is_control(&self) -> bool {
+        match self.opcode {
+            OpCode::Close | OpCode::Ping | OpCode::Pong => true,
+            _ => false,
+        }
+    }
+
     // Test whether the frame is masked.
     #[doc(hidden)]
     #[inline]
@@ -115,6 +162,7 @@ impl Frame {
     #[inline]
     pub fn set_final(&mut self, is_final: bool) -> &mut Frame {
         self.finished = is_final;
+        self.encoded = None;
         self
     }
 
@@ -123,6 +171,7 @@ impl Frame {
     #[inline]
     pub fn set_rsv1(&mut self, has_rsv1: bool) -> &mut Frame {
         self.rsv1 = has_rsv1;
+        self.encoded = None;
         self
     }
 
@@ -131,6 +180,7 @@ impl Frame {
     #[inline]
     pub fn set_rsv2(&mut self, has_rsv2: bool) -> &mut Frame {
         self.rsv2 = has_rsv2;
+        self.encoded = None;
         self
     }
 
@@ -139,6 +189,7 @@ impl Frame {
     #[inline]
     pub fn set_rsv3(&mut self, has_rsv3: bool) -> &mut Frame {
         self.rsv1 = has_rsv3;
+        self.encoded = None;
         self
     }
 
@@ -147,14 +198,17 @@ impl Frame {
     #[inline]
     pub fn set_opcode(&mut self, opcode: OpCode) -> &mut Frame {
         self.opcode = opcode;
+        self.encoded = None;
         self
     }
 
-    /// Edit the frame's payload.
+    /// Edit the frame's payload. If the payload is shared with other frames, such as those of a
+    /// broadcast message, it is copied first.
     #[allow(dead_code)]
     #[inline]
     pub fn payload_mut(&mut self) -> &mut Vec<u8> {
-        &mut self.payload
+        self.encoded = None;
+        self.payload.to_mut()
     }
 
     // Generate a new mask for this frame.
@@ -166,6 +220,7 @@ impl Frame {
     #[inline]
     pub fn set_mask(&mut self) -> &mut Frame {
         self.mask = Some(generate_mask());
+        self.encoded = None;
         self
     }
 
@@ -175,20 +230,51 @@ impl Frame {
     #[inline]
     pub fn remove_mask(&mut self) -> &mut Frame {
         self.mask.and_then(|mask| {
-            Some(apply_mask(&mut self.payload, &mask))
+            Some(apply_mask(self.payload.to_mut(), &mask))
         });
         self.mask = None;
+        self.encoded = None;
         self
     }
 
+    // Format the frame once so that its clones can be written without formatting them again.
+    //
+    // This is used for broadcast frames, which are identical for every server connection. The
+    // encoding is dropped as soon as a clone is changed, for example by masking it for a client
+    // connection or by an `on_send_frame` handler that edits it.
+    #[doc(hidden)]
+    pub fn encode(&mut self) {
+        if self.is_masked() || self.encoded.is_some() {
+            return
+        }
+        let mut buf = Vec::with_capacity(self.len());
+        if self.format(&mut buf).is_ok() {
+            self.encoded = Some(Payload::new(buf));
+        }
+    }
+
+    // Test whether the frame has been encoded in advance.
+    #[doc(hidden)]
+    #[inline]
+    pub fn is_encoded(&self) -> bool {
+        self.encoded.is_some()
+    }
+
     /// Consume the frame into its payload.
     pub fn into_data(self) -> Vec<u8> {
-        self.payload
+        self.payload.into_vec()
     }
 
     /// Create a new data frame.
     #[inline]
     pub fn message(data: Vec<u8>, code: OpCode, finished: bool) -> Frame {
+        Frame::shared(Payload::new(data), code, finished)
+    }
+
+    /// Create a new data frame with a payload that may be shared with other frames, such as the
+    /// frames of the same message sent to other connections.
+    #[inline]
+    pub fn shared(data: Payload, code: OpCode, finished: bool) -> Frame {
         debug_assert!(match code {
             OpCode::Text | OpCode::Binary | OpCode::Continue => true,
             _ => false,
@@ -207,7 +293,7 @@ impl Frame {
     pub fn pong(data: Vec<u8>) -> Frame {
         Frame {
             opcode: OpCode::Pong,
-            payload: data,
+            payload: data.into(),
             .. Frame::default()
         }
     }
@@ -217,7 +303,7 @@ impl Frame {
     pub fn ping(data: Vec<u8>) -> Frame {
         Frame {
             opcode: OpCode::Ping,
-            payload: data,
+            payload: data.into(),
             .. Frame::default()
         }
     }
@@ -240,27 +326,43 @@ impl Frame {
         };
 
         Frame {
-            payload: payload,
+            payload: payload.into(),
             .. Frame::default()
         }
     }
 
     /// Parse the input stream into a frame.
     pub fn parse(cursor: &mut Cursor<Vec<u8>>) -> Result<Option<Frame>> {
-        let size = cursor.get_ref().len() - cursor.position() as usize;
-        let initial = cursor.position();
+        let initial = cursor.position() as usize;
         debug!("Position in buffer {}", initial);
 
-        let mut head = [0u8; 2];
-        if try!(cursor.read(&mut head)) != 2 {
-            cursor.set_position(initial);
+        let (mut frame, header_length, length) = {
+            match try!(Frame::parse_header(&cursor.get_ref()[initial..])) {
+                Some(header) => header,
+                None => return Ok(None),
+            }
+        };
+
+        let end = initial + header_length + length;
+        if cursor.get_ref().len() < end {
             return Ok(None)
         }
+        frame.payload = Payload::from(&cursor.get_ref()[initial + header_length..end]);
+        cursor.set_position(end as u64);
+        Ok(Some(frame))
+    }
 
-        debug!("Parsed headers {:?}", head);
+    // Parse the header at the start of the buffer into a frame with an empty payload. This returns
+    // the frame along with the length of the header and the length of the payload that follows
+    // it, or None if the buffer does not hold the whole header yet.
+    #[doc(hidden)]
+    pub fn parse_header(buf: &[u8]) -> Result<Option<(Frame, usize, usize)>> {
+        if buf.len() < 2 {
+            return Ok(None)
+        }
 
-        let first = head[0];
-        let second = head[1];
+        let first = buf[0];
+        let second = buf[1];
         debug!("First: {:b}", first);
         debug!("Second: {:b}", second);
 
@@ -283,12 +385,11 @@ impl Frame {
         let mut length = (second & 0x7F) as u64;
 
         if length == 126 {
-            let mut length_bytes = [0u8; 2];
-            if try!(cursor.read(&mut length_bytes)) != 2 {
-                cursor.set_position(initial);
+            if buf.len() < 4 {
                 return Ok(None)
             }
-
+            let mut length_bytes = [0u8; 2];
+            length_bytes.copy_from_slice(&buf[2..4]);
             length = unsafe {
                 let mut wide: u16 = transmute(length_bytes);
                 wide = u16::from_be(wide);
@@ -296,12 +397,11 @@ impl Frame {
             } as u64;
             header_length += 2;
         } else if length == 127 {
-            let mut length_bytes = [0u8; 8];
-            if try!(cursor.read(&mut length_bytes)) != 8 {
-                cursor.set_position(initial);
+            if buf.len() < 10 {
                 return Ok(None)
             }
-
+            let mut length_bytes = [0u8; 8];
+            length_bytes.copy_from_slice(&buf[2..10]);
             unsafe { length = transmute(length_bytes); }
             length = u64::from_be(length);
             header_length += 8;
@@ -316,31 +416,22 @@ impl Frame {
             _ => ()
         }
 
+        if length > usize::max_value() as u64 {
+            return Err(Error::new(Kind::Capacity, format!("Received frame with length: {}.", length)))
+        }
+
         let mask = if masked {
-            let mut mask_bytes = [0u8; 4];
-            if try!(cursor.read(&mut mask_bytes)) != 4 {
-                cursor.set_position(initial);
+            if buf.len() < header_length + 4 {
                 return Ok(None)
-            } else {
-                header_length += 4;
-                Some(mask_bytes)
             }
+            let mut mask_bytes = [0u8; 4];
+            mask_bytes.copy_from_slice(&buf[header_length..header_length + 4]);
+            header_length += 4;
+            Some(mask_bytes)
         } else {
             None
         };
 
-        if size < length as usize + header_length {
-            cursor.set_position(initial);
-            return Ok(None)
-        }
-
-        let mut data = Vec::with_capacity(length as usize);
-        if length > 0 {
-            // It is ok to unwrap here because this cursor won't block
-            let read = try!(cursor.try_read_buf(&mut data)).unwrap();
-            debug_assert!(read == length as usize, "Read incorrect payload length!");
-        }
-
         let frame = Frame {
             finished: finished,
             rsv1: rsv1,
@@ -348,15 +439,22 @@ impl Frame {
             rsv3: rsv3,
             opcode: opcode,
             mask: mask,
-            payload: data,
+            payload: Payload::default(),
+            encoded: None,
         };
-        Ok(Some(frame))
+        Ok(Some((frame, header_length, length as usize)))
     }
 
-    /// Write a frame out to a buffer
-    pub fn format<W>(&mut self, w: &mut W) -> Result<()>
-        where W: Write
-    {
+    // Set the payload of a frame made by `parse_header`.
+    #[doc(hidden)]
+    pub fn set_payload(&mut self, payload: Payload) -> &mut Frame {
+        self.payload = payload;
+        self.encoded = None;
+        self
+    }
+
+    // The header of the frame, including the masking key if there is one.
+    fn header(&self) -> Vec<u8> {
         let mut one = 0u8;
         let code: u8 = self.opcode.into();
         if self.is_final() {
@@ -379,49 +477,82 @@ impl Frame {
             two |= 0x80;
         }
 
+        let mut header = Vec::with_capacity(14);
         if self.payload.len() < 126 {
             two |= self.payload.len() as u8;
-            let headers = [one, two];
-            try!(w.write(&headers));
+            header.extend(&[one, two]);
         } else if self.payload.len() <= 65535 {
             two |= 126;
             let length_bytes: [u8; 2] = unsafe {
                 let short = self.payload.len() as u16;
                 transmute(short.to_be())
             };
-            let headers = [one, two, length_bytes[0], length_bytes[1]];
-            try!(w.write(&headers));
+            header.extend(&[one, two]);
+            header.extend(&length_bytes);
         } else {
             two |= 127;
             let length_bytes: [u8; 8] = unsafe {
-                transmute(self.payload.len().to_be())
+                transmute((self.payload.len() as u64).to_be())
             };
-            let headers = [
-                one,
-                two,
-                length_bytes[0],
-                length_bytes[1],
-                length_bytes[2],
-                length_bytes[3],
-                length_bytes[4],
-                length_bytes[5],
-                length_bytes[6],
-                length_bytes[7],
-            ];
-            try!(w.write(&headers));
+            header.extend(&[one, two]);
+            header.extend(&length_bytes);
         }
 
-        if self.is_masked() {
-            let mask = self.mask.take().unwrap();
-            apply_mask(&mut self.payload, &mask);
-            try!(w.write(&mask));
+        if let Some(ref mask) = self.mask {
+            header.extend(mask);
         }
+        header
+    }
 
-        try!(w.write(&self.payload));
+    /// Write a frame out to a buffer
+    pub fn format<W>(&mut self, w: &mut W) -> Result<()>
+        where W: Write
+    {
+        if let Some(ref encoded) = self.encoded {
+            try!(w.write_all(encoded));
+            return Ok(())
+        }
+
+        try!(w.write_all(&self.header()));
+        if let Some(mask) = self.mask.take() {
+            // a shared payload is copied here rather than masked for every frame that shares it
+            apply_mask(self.payload.to_mut(), &mask);
+        }
+        try!(w.write_all(&self.payload));
         Ok(())
     }
+
+    // Consume the frame into the chunks of its wire format, so that it can be queued for writing
+    // without copying the payload. A masked payload is masked in place unless it is shared.
+    #[doc(hidden)]
+    pub fn into_chunks(mut self) -> Vec<Payload> {
+        if let Some(encoded) = self.encoded.take() {
+            return vec![encoded]
+        }
+        let header = Payload::new(self.header());
+        if let Some(mask) = self.mask.take() {
+            apply_mask(self.payload.to_mut(), &mask);
+        }
+        vec![header, self.payload]
+    }
+}
+
+// The encoding is only a cache, so it does not take part in comparisons.
+impl PartialEq for Frame {
+
+    fn eq(&self, other: &Frame) -> bool {
+        self.finished == other.finished &&
+        self.rsv1 == other.rsv1 &&
+        self.rsv2 == other.rsv2 &&
+        self.rsv3 == other.rsv3 &&
+        self.opcode == other.opcode &&
+        self.mask == other.mask &&
+        self.payload == other.payload
+    }
 }
 
+impl Eq for Frame {}
+
 impl Default for Frame {
     fn default() -> Frame {
         Frame {
@@ -431,7 +562,8 @@ impl Default for Frame {
             rsv3: false,
             opcode: OpCode::Close,
             mask: None,
-            payload: Vec::new(),
+            payload: Payload::default(),
+            encoded: None,
         }
     }
 }
@@ -462,10 +594,108 @@ mod test {
     use super::*;
     use protocol::OpCode;
 
+    #[test]
+    fn test_apply_mask() {
+        let mask = [0x6d, 0xb6, 0xb2, 0x80];
+        let data: Vec<u8> = (0..200).map(|i| (i * 7) as u8).collect();
+
+        // every length and every alignment of the start of the buffer
+        for start in 0..16 {
+            for len in 0..(data.len() - start) {
+                let mut expected = data[start..start + len].to_vec();
+                apply_mask_bytes(&mut expected, &mask);
+
+                let mut buf = data.clone();
+                apply_mask(&mut buf[start..start + len], &mask);
+                assert_eq!(&buf[start..start + len], &expected[..]);
+                assert_eq!(&buf[..start], &data[..start]);
+                assert_eq!(&buf[start + len..], &data[start + len..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mask_round_trip() {
+        let mut frame = Frame::message(vec![42; 1000], OpCode::Binary, true);
+        frame.set_mask();
+        let mask = *frame.mask().unwrap();
+        let mut buf = frame.payload().to_vec();
+        apply_mask(&mut buf, &mask);
+        apply_mask(&mut buf, &mask);
+        assert_eq!(&buf[..], &frame.payload()[..]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut frame = Frame::message(vec![7; 200], OpCode::Binary, false);
+        frame.set_mask();
+        let mut buf = Vec::new();
+        frame.format(&mut buf).unwrap();
+
+        for len in 0..buf.len() {
+            let mut partial = Cursor::new(buf[..len].to_vec());
+            assert!(Frame::parse(&mut partial).unwrap().is_none());
+            assert_eq!(partial.position(), 0);
+        }
+
+        let (_, header_length, length) = Frame::parse_header(&buf).unwrap().unwrap();
+        assert_eq!((header_length, length), (8, 200));
+
+        let mut cursor = Cursor::new(buf);
+        let mut parsed = Frame::parse(&mut cursor).unwrap().unwrap();
+        assert_eq!(cursor.position() as usize, cursor.get_ref().len());
+        assert!(!parsed.is_final());
+        assert_eq!(parsed.opcode(), OpCode::Binary);
+        parsed.remove_mask();
+        assert_eq!(parsed.into_data(), vec![7; 200]);
+    }
+
     #[test]
     fn test_display_frame() {
         let f = Frame::message("hi there".into(), OpCode::Text, true);
         let view = format!("{}", f);
         view.contains("payload:");
     }
+
+    #[test]
+    fn test_encoded_frame() {
+        let mut frame = Frame::message(vec![1, 2, 3], OpCode::Binary, true);
+        let mut plain = Vec::new();
+        frame.clone().format(&mut plain).unwrap();
+
+        frame.encode();
+        let mut shared = frame.clone();
+        assert!(shared.is_encoded());
+        let mut encoded = Vec::new();
+        shared.format(&mut encoded).unwrap();
+        assert_eq!(encoded, plain);
+
+        shared.set_final(false);
+        assert!(!shared.is_encoded());
+        assert!(frame.is_encoded());
+
+        let mut masked = frame.clone();
+        masked.set_mask();
+        assert!(!masked.is_encoded());
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut frame = Frame::message(vec![0; 300], OpCode::Binary, true);
+        let mut formatted = Vec::new();
+        frame.clone().format(&mut formatted).unwrap();
+
+        let chunks = frame.clone().into_chunks();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(&chunks[0][..], &[0x82, 126, 1, 44]);
+        assert_eq!(chunks.iter().fold(Vec::new(), |mut buf, chunk| {
+            buf.extend(&chunk[..]);
+            buf
+        }), formatted);
+
+        frame.encode();
+        let chunks = frame.into_chunks();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(&chunks[0][..], &formatted[..]);
+    }
 }
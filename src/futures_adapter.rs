@@ -0,0 +1,378 @@
+//! Connections as futures `Stream`s and `Sink`s of messages.
+//!
+//! The WebSocket runs on its own event loop in a background thread. Messages from the other
+//! endpoint are passed to the `Stream` half of a `Connection`, and messages given to the `Sink`
+//! half are sent through the connection's `Sender`. The `Sink` completes once its messages
+//! have been written to the socket, and stops taking messages while too many of them wait.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! extern crate futures;
+//! extern crate ws;
+//!
+//! use futures::{Future, Sink, Stream};
+//!
+//! # fn main() {
+//! // an echo server
+//! let incoming = ws::futures_adapter::listen("127.0.0.1:3012").unwrap();
+//! incoming.for_each(|conn| {
+//!     let (sink, stream) = conn.split();
+//!     stream.forward(sink).map(|_| ())
+//! }).wait().unwrap();
+//! # }
+//! ```
+use std::borrow::Borrow;
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::task::{self, Task};
+use url;
+
+use communication::Sender;
+use frame::Frame;
+use handler::Handler;
+use handshake::Handshake;
+use message::Message;
+use protocol::{CloseCode, OpCode};
+use result::{Result, Error, Kind};
+use super::WebSocket;
+
+enum Event {
+    Message(Message),
+    Close,
+    Error(Error),
+}
+
+// The most messages that the Sink lets wait for the socket before it stops taking more.
+const MAX_UNWRITTEN: u64 = 64;
+
+fn disconnected() -> Error {
+    Error::new(Kind::Internal, "The WebSocket is no longer running.")
+}
+
+// How far the messages of a connection have been written, shared by its handler and its Sink.
+struct Progress {
+    // the messages queued on the connection, and how many of them had been queued when the
+    // queue was last written out
+    queued: u64,
+    written: u64,
+    closed: bool,
+    // the task waiting for the messages to be written
+    task: Option<Task>,
+}
+
+impl Progress {
+
+    fn new() -> Arc<Mutex<Progress>> {
+        Arc::new(Mutex::new(Progress {
+            queued: 0,
+            written: 0,
+            closed: false,
+            task: None,
+        }))
+    }
+
+    fn notify(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+// Hands the Connection out once it is open, and then forwards its events.
+struct Bridge {
+    opened: UnboundedSender<Result<Connection>>,
+    pending: Option<Connection>,
+    events: UnboundedSender<Event>,
+    progress: Arc<Mutex<Progress>>,
+    client: bool,
+}
+
+impl Bridge {
+
+    fn new(out: Sender, opened: UnboundedSender<Result<Connection>>, client: bool) -> Bridge {
+        let (tx, rx) = unbounded();
+        let progress = Progress::new();
+        Bridge {
+            opened: opened,
+            pending: Some(Connection {
+                out: out,
+                events: rx,
+                progress: progress.clone(),
+                sent: 0,
+                closing: false,
+            }),
+            events: tx,
+            progress: progress,
+            client: client,
+        }
+    }
+
+    fn closed(&mut self) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.closed = true;
+        progress.notify();
+    }
+}
+
+impl Handler for Bridge {
+
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        if let Some(conn) = self.pending.take() {
+            let _ = self.opened.unbounded_send(Ok(conn));
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let _ = self.events.unbounded_send(Event::Message(msg));
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        let _ = self.events.unbounded_send(Event::Close);
+        self.closed();
+    }
+
+    fn on_error(&mut self, err: Error) {
+        if self.pending.take().is_some() {
+            // a client is waiting for its connection to open, but a server must keep accepting
+            // other connections when one of them fails its handshake
+            if self.client {
+                let _ = self.opened.unbounded_send(Err(err));
+            } else {
+                debug!("Dropping a connection that failed before opening: {:?}", err);
+            }
+        } else {
+            let _ = self.events.unbounded_send(Event::Error(err));
+        }
+        self.closed();
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            return Err(Error::new(Kind::Protocol, "Encountered frame with reserved bits set."))
+        }
+        // count each message once, at its last frame
+        match frame.opcode() {
+            OpCode::Text | OpCode::Binary | OpCode::Continue if frame.is_final() => {
+                self.progress.lock().unwrap().queued += 1;
+            }
+            _ => (),
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_drain(&mut self) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.written = progress.queued;
+        progress.notify();
+    }
+}
+
+impl Drop for Bridge {
+
+    // the connection is gone, so nothing that is still waiting will be written
+    fn drop(&mut self) {
+        self.closed();
+    }
+}
+
+/// An open WebSocket connection.
+///
+/// As a `Stream`, it yields the messages from the other endpoint and ends when the connection is
+/// closed. As a `Sink`, it sends messages to the other endpoint, and closing the sink closes
+/// the connection. Use `Stream::split` to use the halves separately.
+///
+/// The `Sink` completes once the connection has written its messages to the socket, and closing
+/// it completes once the connection has closed. It takes no more messages while 64 of them are
+/// waiting to be written, so a slow endpoint slows down the task that sends to it.
+pub struct Connection {
+    out: Sender,
+    events: UnboundedReceiver<Event>,
+    progress: Arc<Mutex<Progress>>,
+    // the messages given to the Sink, and whether it has sent a close frame
+    sent: u64,
+    closing: bool,
+}
+
+impl Connection {
+
+    /// The Sender of the connection, which can be used to send messages without the `Sink`.
+    ///
+    /// Messages sent with it are counted together with those of the `Sink`, so while both are
+    /// used at once, the `Sink` may complete before all of its messages have been written.
+    pub fn sender(&self) -> &Sender {
+        &self.out
+    }
+
+    // Whether the first `count` messages have been written or the connection has closed. If not,
+    // the current task is woken up once more of them have been written.
+    fn written(&self, count: u64) -> bool {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.closed || progress.written >= count {
+            true
+        } else {
+            progress.task = Some(task::current());
+            false
+        }
+    }
+}
+
+impl fmt::Debug for Connection {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connection {{ token: {:?} }}", self.out.token())
+    }
+}
+
+impl Stream for Connection {
+    type Item = Message;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Message>, Error> {
+        match self.events.poll() {
+            Ok(Async::Ready(Some(Event::Message(msg)))) => Ok(Async::Ready(Some(msg))),
+            Ok(Async::Ready(Some(Event::Error(err)))) => Err(err),
+            Ok(Async::Ready(Some(Event::Close))) | Ok(Async::Ready(None)) | Err(_) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Sink for Connection {
+    type SinkItem = Message;
+    type SinkError = Error;
+
+    fn start_send(&mut self, msg: Message) -> StartSend<Message, Error> {
+        if self.sent >= MAX_UNWRITTEN && !self.written(self.sent + 1 - MAX_UNWRITTEN) {
+            return Ok(AsyncSink::NotReady(msg))
+        }
+        try!(self.out.send(msg));
+        self.sent += 1;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        if self.written(self.sent) {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        if !self.closing {
+            try!(self.out.close(CloseCode::Normal));
+            self.closing = true;
+        }
+        // only a closed connection has written everything
+        if self.written(u64::max_value()) {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A future resolving to a client connection once the handshake is complete.
+pub struct Connect {
+    opened: UnboundedReceiver<Result<Connection>>,
+}
+
+impl Future for Connect {
+    type Item = Connection;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Connection, Error> {
+        match self.opened.poll() {
+            Ok(Async::Ready(Some(conn))) => conn.map(Async::Ready),
+            Ok(Async::Ready(None)) | Err(_) => Err(disconnected()),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A stream of the connections accepted by a server.
+///
+/// The server keeps running until `shutdown` is called on the broadcaster.
+pub struct Incoming {
+    broadcaster: Sender,
+    local_addr: SocketAddr,
+    opened: UnboundedReceiver<Result<Connection>>,
+}
+
+impl Incoming {
+
+    /// A Sender for all connections of the server, which can also shut it down.
+    pub fn broadcaster(&self) -> &Sender {
+        &self.broadcaster
+    }
+
+    /// The address that the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Stream for Incoming {
+    type Item = Connection;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Connection>, Error> {
+        match self.opened.poll() {
+            Ok(Async::Ready(Some(conn))) => conn.map(|conn| Async::Ready(Some(conn))),
+            Ok(Async::Ready(None)) | Err(_) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Connect to a WebSocket server on a background event loop.
+pub fn connect<U>(url: U) -> Result<Connect>
+    where U: Borrow<str>
+{
+    let url = try!(
+        url::Url::parse(url.borrow())
+            .map_err(|err| Error::new(
+                Kind::Internal,
+                format!("Unable to parse {} as url due to {:?}", url.borrow(), err))));
+
+    let (tx, rx) = unbounded();
+    let errors = tx.clone();
+    let mut ws = try!(WebSocket::new(move |out: Sender| Bridge::new(out, tx.clone(), true)));
+    try!(ws.connect(url));
+    thread::spawn(move || {
+        if let Err(err) = ws.run() {
+            let _ = errors.unbounded_send(Err(err));
+        }
+    });
+    Ok(Connect { opened: rx })
+}
+
+/// Listen for WebSocket connections on a background event loop. The address is bound before
+/// this returns, so clients may connect as soon as it does.
+pub fn listen<A>(addr: A) -> Result<Incoming>
+    where A: ToSocketAddrs + fmt::Debug
+{
+    let (tx, rx) = unbounded();
+    let errors = tx.clone();
+    let ws = try!(try!(WebSocket::new(move |out: Sender| Bridge::new(out, tx.clone(), false))).bind(addr));
+    let broadcaster = ws.broadcaster();
+    let local_addr = try!(ws.local_addr());
+    thread::spawn(move || {
+        if let Err(err) = ws.run() {
+            let _ = errors.unbounded_send(Err(err));
+        }
+    });
+    Ok(Incoming {
+        broadcaster: broadcaster,
+        local_addr: local_addr,
+        opened: rx,
+    })
+}
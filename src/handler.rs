@@ -1,7 +1,7 @@
 use url;
 use log::LogLevel::Error as ErrorLevel;
 #[cfg(all(not(windows), feature="ssl"))]
-use openssl::ssl::{Ssl, SslContext, SslMethod, IntoSsl};
+use openssl::ssl::Ssl;
 
 use message::Message;
 use frame::Frame;
@@ -88,6 +88,33 @@ pub trait Handler {
         Response::from_request(req)
     }
 
+    /// A method for handling plain HTTP requests that arrive on the WebSocket port.
+    ///
+    /// This method is called instead of `on_request` when a request does not ask to upgrade the
+    /// connection to a WebSocket, such as a load balancer health check or a request for a static
+    /// page. Implementors can answer with any Response, for example one built with
+    /// `Response::new`. After the response is written, the connection will wait for another
+    /// request unless either side sent `Connection: close`. Returning an error will send a 400 or
+    /// 500 response and close the connection.
+    ///
+    /// This method will not be called when the handler represents a client endpoint. By default,
+    /// plain HTTP requests are rejected.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// if req.resource() == "/health" {
+    ///     Ok(Response::new(200, "OK", b"OK".to_vec()))
+    /// } else {
+    ///     Ok(Response::new(404, "Not Found", Vec::new()))
+    /// }
+    /// ```
+    #[inline]
+    fn on_http_request(&mut self, req: &Request) -> Result<Response> {
+        debug!("Handler received plain HTTP request:\n{}", req);
+        Err(Error::new(Kind::Protocol, "Received an HTTP request that is not a WebSocket handshake."))
+    }
+
     /// A method for handling the low-level workings of the response portion of the WebSocket
     /// handshake.
     ///
@@ -147,6 +174,15 @@ pub trait Handler {
         }
     }
 
+    /// Called when every frame that was queued on the connection has been written to the socket.
+    ///
+    /// Together with `on_send_frame`, this lets a handler follow how far its messages have been
+    /// sent, for example to stop producing messages while the other endpoint is slow to read.
+    #[inline]
+    fn on_drain(&mut self) {
+        debug!("Handler wrote out all queued frames.");
+    }
+
     // constructors
 
     /// A method for creating the initial handshake request for WebSocket clients.
@@ -170,14 +206,20 @@ pub trait Handler {
         Request::from_url(url)
     }
 
-    /// A method for obtaining an Ssl object for use in wss connections.
+    /// A method for obtaining an Ssl object for use in wss connections. Servers call it when no
+    /// `TlsServerConfig` was given to `Builder::with_tls`, and clients call it when no
+    /// `TlsClientConfig` was given to `Builder::with_tls_client`.
+    ///
+    /// Override this method to return the Ssl object used to encrypt the connection. The default
+    /// implementation returns None, so that clients verify servers with the default
+    /// `TlsClientConfig`.
     ///
-    /// Override this method to customize the Ssl object used to encrypt the connection.
+    /// When the `rustls` feature is enabled as well, connections use rustls and this method is
+    /// not called.
     #[inline]
     #[cfg(all(not(windows), feature="ssl"))]
-    fn build_ssl(&mut self) -> Result<Ssl> {
-        let context = try!(SslContext::new(SslMethod::Tlsv1));
-        (&context).into_ssl().map_err(Error::from)
+    fn build_ssl(&mut self) -> Result<Option<Ssl>> {
+        Ok(None)
     }
 }
 
@@ -243,6 +285,8 @@ mod test {
             response: res,
             peer_addr: None,
             local_addr: None,
+            server_name: None,
+            tls: None,
         }).unwrap();
         h.on_message(message::Message::Text("testme".to_owned())).unwrap();
         h.on_close(CloseCode::Normal, "");
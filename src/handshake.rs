@@ -73,6 +73,173 @@ fn encode_base64(data: &[u8]) -> String {
     String::from_utf8(encoded).unwrap()
 }
 
+fn decode_base64(data: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(data.len() / 4 * 3);
+    let mut acc = 0u32;
+    let mut bits = 0;
+
+    for &byte in data.iter().take_while(|&&byte| byte != b'=') {
+        let val = match BASE64.iter().position(|&enc| enc == byte) {
+            Some(val) => val,
+            None => return None,
+        };
+        acc = acc << 6 | val as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+// The length of the first complete request in the buffer, so that the bytes of a request
+// pipelined after it can be kept.
+pub fn request_length(buf: &[u8]) -> Option<usize> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => Some(len),
+        _ => None,
+    }
+}
+
+/// Get the name of an extension from its configuration in a `Sec-WebSocket-Extensions` header,
+/// for example `permessage-deflate` from `permessage-deflate; client_max_window_bits`.
+pub fn extension_name(ext: &str) -> &str {
+    ext.split(';').next().unwrap_or("").trim()
+}
+
+fn basic_credentials(username: &str, password: &str) -> String {
+    format!("Basic {}", encode_base64(format!("{}:{}", username, password).as_bytes()))
+}
+
+/// Credentials sent by a client in the `Authorization` header of the handshake request.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Authorization {
+    /// HTTP Basic credentials.
+    Basic {
+        /// The user name.
+        username: String,
+        /// The password, which may be empty.
+        password: String,
+    },
+    /// A bearer token, such as an OAuth 2.0 access token.
+    Bearer(String),
+}
+
+/// Extra details to use in the handshake request of an outgoing connection.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ws::{WebSocket, ConnectOptions};
+///
+/// let mut ws = WebSocket::new(|_| |_| Ok(())).unwrap();
+/// let mut options = ConnectOptions::new();
+/// options.bearer("secret-token").header("X-Client", "ws-rs");
+///
+/// ws.connect_with("ws://127.0.0.1:3012".parse().unwrap(), options).unwrap();
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ConnectOptions {
+    headers: Vec<(String, Vec<u8>)>,
+    protocols: Vec<String>,
+    extensions: Vec<String>,
+    required: Vec<String>,
+}
+
+impl ConnectOptions {
+
+    /// Create new options that leave the handshake request unchanged.
+    pub fn new() -> ConnectOptions {
+        ConnectOptions::default()
+    }
+
+    /// Set a header on the handshake request. This replaces the first header with the same name
+    /// in the request built by `Handler::build_request`, if there is one.
+    pub fn header<K, V>(&mut self, key: K, value: V) -> &mut ConnectOptions
+        where K: Into<String>, V: Into<Vec<u8>>
+    {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Authenticate with a bearer token using the `Authorization` header.
+    pub fn bearer(&mut self, token: &str) -> &mut ConnectOptions {
+        self.header("Authorization", format!("Bearer {}", token))
+    }
+
+    /// Authenticate with HTTP Basic credentials using the `Authorization` header. Credentials
+    /// found in the userinfo part of the URL are used automatically.
+    pub fn basic(&mut self, username: &str, password: &str) -> &mut ConnectOptions {
+        self.header("Authorization", basic_credentials(username, password))
+    }
+
+    /// Offer a subprotocol to the server. Protocols are offered in the order that they are added,
+    /// which should be the order of preference. If the server selects a protocol that was not
+    /// offered, the handshake will fail.
+    pub fn protocol(&mut self, protocol: &str) -> &mut ConnectOptions {
+        self.protocols.push(protocol.into());
+        self
+    }
+
+    /// Offer an extension to the server, such as `permessage-deflate; client_max_window_bits`.
+    /// The handshake will fail with an Extension (1010) close code if the server agrees to an
+    /// extension that was not offered.
+    pub fn extension(&mut self, ext: &str) -> &mut ConnectOptions {
+        self.extensions.push(ext.into());
+        self
+    }
+
+    /// Offer an extension to the server and require the server to agree to it. If the server's
+    /// response omits the extension, the connection will be failed with an Extension (1010)
+    /// close code listing the missing extensions.
+    pub fn require_extension(&mut self, ext: &str) -> &mut ConnectOptions {
+        self.required.push(extension_name(ext).into());
+        self.extension(ext)
+    }
+
+    /// Get the names of the extensions that the server must agree to.
+    #[doc(hidden)]
+    pub fn required_extensions(&self) -> &Vec<String> {
+        &self.required
+    }
+
+    /// Apply these options to a handshake request.
+    #[doc(hidden)]
+    pub fn apply(&self, req: &mut Request) {
+        for &(ref key, ref val) in self.headers.iter() {
+            if let Some(existing) = req.header_mut(key) {
+                *existing = val.clone();
+                continue
+            }
+            req.headers_mut().push((key.clone(), val.clone()))
+        }
+        for protocol in self.protocols.iter() {
+            req.add_protocol(protocol)
+        }
+        for ext in self.extensions.iter() {
+            req.add_extension(ext)
+        }
+    }
+}
+
+/// Details of the TLS session of an encrypted connection.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TlsInfo {
+    /// The negotiated protocol version, such as `TLSv1.2`.
+    pub version: String,
+    /// The name of the negotiated cipher suite.
+    pub cipher: String,
+    /// The protocol agreed to with ALPN, if any.
+    pub alpn_protocol: Option<String>,
+    /// The DER encoded certificates presented by the other endpoint, starting with its own
+    /// certificate. This is empty if the other endpoint sent no certificate.
+    pub peer_certificates: Vec<Vec<u8>>,
+}
+
 /// A struct representing the two halves of the WebSocket handshake.
 #[derive(Debug)]
 pub struct Handshake {
@@ -85,10 +252,21 @@ pub struct Handshake {
     pub peer_addr: Option<SocketAddr>,
     /// The socket address of this enpoint.
     pub local_addr: Option<SocketAddr>,
+    /// The host name that the client asked for with SNI when the connection is encrypted. Servers
+    /// hosting several domains on one listener can use it to tell them apart.
+    pub server_name: Option<String>,
+    /// Details of the TLS session, or None if the connection is not encrypted.
+    pub tls: Option<TlsInfo>,
 }
 
 impl Handshake {
 
+    /// Whether the connection is encrypted.
+    #[inline]
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
     /// Get the IP address of the remote connection.
     ///
     /// This is the preferred method of obtaining the client's IP address.
@@ -112,6 +290,13 @@ impl Handshake {
         }))
     }
 
+    /// Get the subprotocol that both endpoints agreed to use, if any.
+    #[allow(dead_code)]
+    #[inline]
+    pub fn protocol(&self) -> Result<Option<&str>> {
+        self.response.protocol()
+    }
+
 }
 
 
@@ -165,6 +350,17 @@ impl Request {
         }
     }
 
+    /// Check whether the request asks to upgrade the connection to a WebSocket.
+    ///
+    /// Requests that do not are plain HTTP requests, which are passed to
+    /// `Handler::on_http_request` instead of `Handler::on_request`.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .and_then(|upgrade| from_utf8(upgrade).ok())
+            .map(|upgrade| upgrade.split(',').any(|proto| proto.trim().to_lowercase() == "websocket"))
+            .unwrap_or(false)
+    }
+
     /// Get the unhashed WebSocket key sent in the request.
     pub fn key(&self) -> Result<&Vec<u8>> {
         self.header("sec-websocket-key")
@@ -278,6 +474,38 @@ impl Request {
         }
     }
 
+    /// Get the credentials sent in the `Authorization` header.
+    ///
+    /// Only the Basic and Bearer schemes are understood. If the header is missing or uses another
+    /// scheme, this will return `Ok(None)`.
+    #[allow(dead_code)]
+    pub fn authorization(&self) -> Result<Option<Authorization>> {
+        if let Some(auth) = self.header("authorization") {
+            let auth = try!(from_utf8(auth)).trim();
+            let (scheme, credentials) = match auth.find(' ') {
+                Some(pos) => (&auth[..pos], auth[pos..].trim()),
+                None => return Ok(None),
+            };
+
+            match &scheme.to_lowercase()[..] {
+                "basic" => {
+                    let decoded = try!(decode_base64(credentials.as_bytes()).ok_or(
+                        Error::new(Kind::Protocol, "Unable to decode Basic credentials.")));
+                    let decoded = try!(String::from_utf8(decoded).map_err(|err| err.utf8_error()));
+                    let mut parts = decoded.splitn(2, ':');
+                    let username = parts.next().unwrap_or("").to_owned();
+                    let password = try!(parts.next().ok_or(
+                        Error::new(Kind::Protocol, "Basic credentials are missing a colon."))).to_owned();
+                    Ok(Some(Authorization::Basic { username: username, password: password }))
+                }
+                "bearer" => Ok(Some(Authorization::Bearer(credentials.to_owned()))),
+                _ => Ok(None),
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Get the IP address of the client.
     ///
     /// This method will attempt to retrieve the most likely IP address of the requester
@@ -332,8 +560,11 @@ impl Request {
     }
 
     /// Construct a new WebSocket handshake HTTP request from a url.
+    ///
+    /// If the url contains credentials, such as `ws://user:pass@host/`, they will be sent in a
+    /// Basic `Authorization` header.
     pub fn from_url(url: &url::Url) -> Result<Request> {
-        let req = Request {
+        let mut req = Request {
             path: format!(
                 "{}{}",
                 url.serialize_path().unwrap_or("/".to_owned()),
@@ -354,6 +585,15 @@ impl Request {
             ],
         };
 
+        let username = url.lossy_percent_decode_username().unwrap_or(String::new());
+        let password = url.lossy_percent_decode_password();
+        if !username.is_empty() || password.is_some() {
+            req.headers.push((
+                "Authorization".into(),
+                basic_credentials(&username, &password.unwrap_or(String::new())).into(),
+            ));
+        }
+
         debug!("Built request from URL:\n{}", req);
 
         Ok(req)
@@ -397,13 +637,29 @@ pub struct Response {
     status: u16,
     reason: String,
     headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
 }
 
 impl Response {
     // TODO: resolve the overlap with Request
 
+    /// Construct a new HTTP response with the given status, reason and body.
+    ///
+    /// This is useful for answering plain HTTP requests in `Handler::on_http_request`. A
+    /// `Content-Length` header will be added when the response is written unless one is set.
+    pub fn new<R>(status: u16, reason: R, body: Vec<u8>) -> Response
+        where R: Into<String>
+    {
+        Response {
+            status: status,
+            reason: reason.into(),
+            headers: Vec::new(),
+            body: body,
+        }
+    }
+
     /// Get the value of the first instance of an HTTP header.
-    fn header(&self, header: &str) -> Option<&Vec<u8>> {
+    pub fn header(&self, header: &str) -> Option<&Vec<u8>> {
         self.headers
             .iter()
             .find(|&&(ref key, _)| key.to_lowercase() == header.to_lowercase())
@@ -462,6 +718,20 @@ impl Response {
         self.reason = reason.into()
     }
 
+    /// Get the body of the response.
+    #[allow(dead_code)]
+    #[inline]
+    pub fn body(&self) -> &Vec<u8> {
+        &self.body
+    }
+
+    /// Edit the body of the response.
+    #[allow(dead_code)]
+    #[inline]
+    pub fn body_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.body
+    }
+
     /// Get the hashed WebSocket key.
     pub fn key(&self) -> Result<&Vec<u8>> {
         self.header("sec-websocket-accept").ok_or(Error::new(Kind::Protocol, "Unable to parse WebSocket key."))
@@ -487,6 +757,24 @@ impl Response {
         self.headers_mut().push(("Sec-WebSocket-Protocol".into(), protocol.into()))
     }
 
+    /// Select the first of the supported protocols, given in order of preference, that the client
+    /// offered in the request and set it as the protocol of this response. Returns the selected
+    /// protocol, or `None` if the client did not offer any of the supported protocols.
+    pub fn select_protocol<'s, S>(&mut self, req: &Request, supported: &'s [S]) -> Result<Option<&'s str>>
+        where S: AsRef<str>
+    {
+        let selected = {
+            let offered = try!(req.protocols());
+            supported.iter()
+                     .map(|proto| proto.as_ref())
+                     .find(|proto| offered.iter().any(|offer| *offer == *proto))
+        };
+        if let Some(proto) = selected {
+            self.set_protocol(proto)
+        }
+        Ok(selected)
+    }
+
     /// Get the extensions that the server has decided to use. If these are unacceptable, it is
     /// appropriate to send an Extension close code.
     #[allow(dead_code)]
@@ -542,6 +830,7 @@ impl Response {
                 status: res.code.unwrap(),
                 reason: res.reason.unwrap().into(),
                 headers: res.headers.iter().map(|h| (h.name.into(), h.value.into())).collect(),
+                body: Vec::new(),
             }))
         } else {
             Ok(None)
@@ -560,6 +849,7 @@ impl Response {
                 ("Sec-WebSocket-Accept".into(), try!(req.hashed_key()).into()),
                 ("Upgrade".into(), "websocket".into()),
             ],
+            body: Vec::new(),
         };
 
         debug!("Built response from request:\n{}", res);
@@ -576,7 +866,12 @@ impl Response {
             try!(w.write(val));
             try!(write!(w, "\r\n"));
         }
+        // responses that don't switch protocols need a length so that the connection can be reused
+        if self.status != 101 && self.header("content-length").is_none() {
+            try!(write!(w, "Content-Length: {}\r\n", self.body.len()));
+        }
         try!(write!(w, "\r\n"));
+        try!(w.write(&self.body));
         Ok(())
     }
 }
@@ -600,6 +895,7 @@ impl fmt::Display for Response {
 mod test {
     #![allow(unused_imports, unused_variables, dead_code)]
     use std::io::Write;
+    use url;
     use super::*;
 
     #[test]
@@ -621,6 +917,8 @@ mod test {
             response: res,
             peer_addr: None,
             local_addr: None,
+            server_name: None,
+            tls: None,
         };
         assert_eq!(shake.remote_addr().unwrap().unwrap(), "192.168.1.1");
     }
@@ -643,7 +941,79 @@ mod test {
             response: res,
             peer_addr: None,
             local_addr: None,
+            server_name: None,
+            tls: None,
         };
         assert_eq!(shake.remote_addr().unwrap().unwrap(), "192.0.2.43");
     }
+
+    #[test]
+    fn test_plain_http_request() {
+        let mut buf = Vec::with_capacity(2048);
+        write!(
+            &mut buf,
+            "GET /health HTTP/1.1\r\n\
+            Host: localhost\r\n\r\n").unwrap();
+
+        let req = Request::parse(&buf).unwrap().unwrap();
+        assert!(!req.is_websocket_upgrade());
+
+        let res = Response::new(200, "OK", b"healthy".to_vec());
+        let mut out = Vec::new();
+        res.format(&mut out).unwrap();
+        assert_eq!(&out[..], &b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nhealthy"[..]);
+    }
+
+    #[test]
+    fn test_url_credentials() {
+        let url = url::Url::parse("ws://aladdin:opensesame@127.0.0.1:3012").unwrap();
+        let req = Request::from_url(&url).unwrap();
+        assert_eq!(req.header("authorization").unwrap(), b"Basic YWxhZGRpbjpvcGVuc2VzYW1l");
+        assert_eq!(req.authorization().unwrap().unwrap(), Authorization::Basic {
+            username: "aladdin".into(),
+            password: "opensesame".into(),
+        });
+    }
+
+    #[test]
+    fn test_bearer_option() {
+        let url = url::Url::parse("ws://aladdin:opensesame@127.0.0.1:3012").unwrap();
+        let mut req = Request::from_url(&url).unwrap();
+        let mut options = ConnectOptions::new();
+        options.bearer("token").header("X-Custom", "value");
+        options.apply(&mut req);
+
+        assert_eq!(req.authorization().unwrap().unwrap(), Authorization::Bearer("token".into()));
+        assert_eq!(req.header("x-custom").unwrap(), b"value");
+        assert_eq!(req.headers().iter().filter(|&&(ref key, _)| key == "Authorization").count(), 1);
+    }
+
+    #[test]
+    fn test_select_protocol() {
+        let url = url::Url::parse("ws://127.0.0.1:3012").unwrap();
+        let mut req = Request::from_url(&url).unwrap();
+        let mut options = ConnectOptions::new();
+        options.protocol("chat.v1").protocol("chat.v2");
+        options.apply(&mut req);
+        assert_eq!(req.protocols().unwrap(), vec!["chat.v1", "chat.v2"]);
+
+        let mut res = Response::from_request(&req).unwrap();
+        assert_eq!(res.select_protocol(&req, &["chat.v3"]).unwrap(), None);
+        assert_eq!(res.protocol().unwrap(), None);
+        assert_eq!(res.select_protocol(&req, &["chat.v3", "chat.v2", "chat.v1"]).unwrap(), Some("chat.v2"));
+        assert_eq!(res.protocol().unwrap(), Some("chat.v2"));
+    }
+
+    #[test]
+    fn test_extension_options() {
+        let url = url::Url::parse("ws://127.0.0.1:3012").unwrap();
+        let mut req = Request::from_url(&url).unwrap();
+        let mut options = ConnectOptions::new();
+        options.extension("x-foo").require_extension("permessage-deflate; client_max_window_bits");
+        options.apply(&mut req);
+
+        assert_eq!(req.extensions().unwrap(), vec!["x-foo", "permessage-deflate; client_max_window_bits"]);
+        assert_eq!(options.required_extensions(), &vec![String::from("permessage-deflate")]);
+        assert_eq!(extension_name(" x-foo ; a=1"), "x-foo");
+    }
 }
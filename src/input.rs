@@ -0,0 +1,349 @@
+use std::io;
+use std::ptr;
+use std::cmp::{min, max};
+
+use mio::TryRead;
+
+use frame::{Frame, apply_mask};
+use payload::Payload;
+use result::{Result, Error, Kind};
+use stream::Stream;
+
+// The longest possible frame header, which always has to fit in the buffer.
+const MAX_HEADER: usize = 14;
+
+/// The buffer of bytes read from a connection.
+///
+/// Frame headers are parsed where they lie in the buffer, and the payload of a frame that fits in
+/// the buffer is unmasked in place and handed out as a slice of the buffer, without being copied.
+/// While frames still share the buffer, the next read goes into a new buffer, so only the bytes
+/// that have not been parsed yet are ever copied. When a frame is larger than the buffer, its
+/// payload is read straight from the socket into a Vec of its own, which becomes the payload of
+/// the frame. That Vec grows as the payload arrives rather than being allocated for the length
+/// claimed by the header. The buffer itself therefore stays at its initial size instead of
+/// growing to fit the largest message that was ever received.
+pub struct Input {
+    buf: Payload,
+    // the unparsed bytes are buf[start..end]
+    start: usize,
+    end: usize,
+    capacity: usize,
+    // whether frames larger than the capacity may be received
+    grow: bool,
+    // a frame whose payload is being read into its own buffer, the bytes read so far and the
+    // length of the payload
+    large: Option<(Frame, Vec<u8>, usize)>,
+}
+
+impl Input {
+
+    pub fn new(capacity: usize, grow: bool) -> Input {
+        let capacity = max(capacity, MAX_HEADER);
+        Input {
+            buf: Payload::new(vec![0; capacity]),
+            start: 0,
+            end: 0,
+            capacity: capacity,
+            grow: grow,
+            large: None,
+        }
+    }
+
+    /// Whether there are no buffered bytes.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end && self.large.is_none()
+    }
+
+    /// Add bytes that were read elsewhere, such as those that followed the handshake response.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.compact();
+        let end = self.end;
+        let buf = self.buf_mut();
+        if buf.len() - end < data.len() {
+            buf.resize(end + data.len(), 0);
+        }
+        buf[end..end + data.len()].copy_from_slice(data);
+        self.end += data.len();
+    }
+
+    /// Read from the stream into the buffer, or into the payload of a large frame.
+    ///
+    /// This returns `Ok(Some(0))` when the other endpoint has closed the stream, and `Ok(None)`
+    /// when the stream would block or the buffer needs to be parsed before reading more.
+    pub fn read_from(&mut self, stream: &mut Stream) -> io::Result<Option<usize>> {
+        if let Some((_, ref mut payload, length)) = self.large {
+            let filled = payload.len();
+            if filled == length {
+                return Ok(None)
+            }
+            // at most double what has arrived so far, so a peer has to send the bytes it claims
+            let step = min(length - filled, max(filled, self.capacity));
+            payload.resize(filled + step, 0);
+            let res = stream.try_read(&mut payload[filled..]);
+            let read = match res {
+                Ok(Some(len)) => len,
+                _ => 0,
+            };
+            payload.truncate(filled + read);
+            return res
+        }
+
+        if self.end == self.buf.len() {
+            self.compact();
+            if self.end == self.buf.len() {
+                return Ok(None)
+            }
+        }
+        self.buf_mut();
+        let end = self.end;
+        let res = try!(stream.try_read(&mut self.buf_mut()[end..]));
+        if let Some(len) = res {
+            self.end += len;
+        }
+        Ok(res)
+    }
+
+    /// Parse the next complete frame, and whether it was masked. The payload has already been
+    /// unmasked.
+    pub fn next_frame(&mut self) -> Result<Option<(Frame, bool)>> {
+        if let Some((_, ref payload, length)) = self.large {
+            if payload.len() < length {
+                return Ok(None)
+            }
+        }
+        if let Some((mut frame, payload, _)) = self.large.take() {
+            let masked = frame.is_masked();
+            // the payload is not shared with anything yet, so it is unmasked in place
+            frame.set_payload(Payload::new(payload)).remove_mask();
+            return Ok(Some((frame, masked)))
+        }
+
+        let (mut frame, header_length, length) = {
+            match try!(Frame::parse_header(&self.buf[self.start..self.end])) {
+                Some(header) => header,
+                None => {
+                    self.make_room();
+                    return Ok(None)
+                }
+            }
+        };
+
+        let frame_length = match header_length.checked_add(length) {
+            Some(frame_length) => frame_length,
+            None => return Err(Error::new(Kind::Capacity, format!("Received frame with length: {}.", length))),
+        };
+
+        if frame_length > self.buf.len() {
+            // read the payload of this frame into a buffer of its own
+            if !self.grow && length > self.capacity {
+                return Err(Error::new(Kind::Capacity, "Received a frame larger than the input buffer."))
+            }
+            let data_start = self.start + header_length;
+            let payload = self.buf[data_start..self.end].to_vec();
+            self.start = 0;
+            self.end = 0;
+            self.shrink();
+            self.large = Some((frame, payload, length));
+            return self.next_frame()
+        }
+
+        if self.start + frame_length > self.end {
+            self.make_room();
+            return Ok(None)
+        }
+
+        let masked = frame.is_masked();
+        if let Some(&mask) = frame.mask() {
+            // this may move the unparsed bytes to a new buffer
+            self.buf_mut();
+            let data_start = self.start + header_length;
+            apply_mask(&mut self.buf_mut()[data_start..data_start + length], &mask);
+        }
+        // the header has no payload yet, so this only forgets the mask
+        frame.remove_mask();
+
+        let data_start = self.start + header_length;
+        let data_end = data_start + length;
+        frame.set_payload(self.buf.slice(data_start, data_end));
+        self.start = data_end;
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+            self.shrink();
+        }
+        Ok(Some((frame, masked)))
+    }
+
+    // Get the buffer to read into. If frames still share the buffer, the unparsed bytes are moved
+    // to a new one instead of changing the bytes under those frames.
+    fn buf_mut(&mut self) -> &mut Vec<u8> {
+        if self.buf.get_mut().is_none() {
+            let len = self.end - self.start;
+            let mut buf = vec![0; max(self.buf.len(), self.capacity)];
+            buf[..len].copy_from_slice(&self.buf[self.start..self.end]);
+            self.buf = Payload::new(buf);
+            self.start = 0;
+            self.end = len;
+        }
+        self.buf.get_mut().expect("Input buffer was not unique after replacing it.")
+    }
+
+    // Move the unparsed bytes to the front of the buffer when the end of the buffer is reached.
+    fn make_room(&mut self) {
+        if self.end == self.buf.len() {
+            self.compact()
+        }
+    }
+
+    fn compact(&mut self) {
+        // replacing a shared buffer already moves the unparsed bytes to the front
+        self.buf_mut();
+        if self.start > 0 {
+            let len = self.end - self.start;
+            let start = self.start;
+            {
+                let buf = self.buf_mut();
+                unsafe {
+                    ptr::copy(buf.as_ptr().offset(start as isize), buf.as_mut_ptr(), len);
+                }
+            }
+            self.start = 0;
+            self.end = len;
+        }
+    }
+
+    // Give back memory that `extend` added beyond the capacity once it is no longer needed.
+    fn shrink(&mut self) {
+        if self.end == 0 && self.buf.len() > self.capacity {
+            self.buf = Payload::new(vec![0; self.capacity]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use protocol::OpCode;
+    use stream::test::{pair, write_all};
+
+    fn encode(data: Vec<u8>, masked: bool) -> Vec<u8> {
+        let mut frame = Frame::message(data, OpCode::Binary, true);
+        if masked {
+            frame.set_mask();
+        }
+        let mut buf = Vec::new();
+        frame.format(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_frames_in_place() {
+        let mut input = Input::new(64, true);
+        let mut data = encode(vec![1, 2, 3], true);
+        data.extend(encode(vec![4, 5], false));
+        data.extend(&encode(vec![6; 10], true)[..4]);
+        input.extend(&data);
+
+        let (first, masked) = input.next_frame().unwrap().unwrap();
+        assert!(masked);
+        assert!(!first.is_masked());
+        assert_eq!(&first.payload_shared()[..], &[1, 2, 3]);
+        let (second, masked) = input.next_frame().unwrap().unwrap();
+        assert!(!masked);
+        assert_eq!(&second.payload_shared()[..], &[4, 5]);
+        assert!(input.next_frame().unwrap().is_none());
+        assert!(!input.is_empty());
+
+        // the payloads are slices of the buffer rather than copies
+        let buf = input.buf.as_ptr();
+        assert_eq!(first.payload_shared().as_ptr(), unsafe { buf.offset(6) });
+        assert_eq!(second.payload_shared().as_ptr(), unsafe { buf.offset(11) });
+    }
+
+    #[test]
+    fn test_shared_buffer_is_replaced() {
+        let mut input = Input::new(64, true);
+        let mut data = encode(vec![1, 2, 3], false);
+        let rest = encode(vec![4; 10], true);
+        data.extend(&rest[..4]);
+        input.extend(&data);
+
+        let (frame, _) = input.next_frame().unwrap().unwrap();
+        assert!(input.next_frame().unwrap().is_none());
+
+        // the frame keeps its bytes while the rest of the next frame arrives in a new buffer
+        input.extend(&rest[4..]);
+        let (next, _) = input.next_frame().unwrap().unwrap();
+        assert_eq!(&frame.payload_shared()[..], &[1, 2, 3]);
+        assert_eq!(&next.payload_shared()[..], &[4; 10]);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn test_large_frame() {
+        let mut input = Input::new(MAX_HEADER, true);
+        let data = encode((0..100).collect(), true);
+        input.extend(&data[..20]);
+        assert!(input.next_frame().unwrap().is_none());
+
+        // the rest of the payload goes straight into the frame's own buffer
+        input.large.as_mut().unwrap().1.extend_from_slice(&data[20..]);
+        assert_eq!(input.buf.len(), MAX_HEADER);
+        let (frame, masked) = input.next_frame().unwrap().unwrap();
+        assert!(masked);
+        assert_eq!(frame.into_data(), (0..100).collect::<Vec<u8>>());
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn test_large_frame_limit() {
+        let mut input = Input::new(MAX_HEADER, false);
+        input.extend(&encode(vec![0; 100], false)[..10]);
+        assert!(input.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_huge_length() {
+        // a header claiming a terabyte of payload
+        let mut input = Input::new(64, true);
+        input.extend(&[0x82, 127, 0, 0, 1, 0, 0, 0, 0, 0, 1, 2]);
+        assert!(input.next_frame().unwrap().is_none());
+        assert!(input.large.as_ref().unwrap().1.capacity() < 1024);
+
+        let mut input = Input::new(64, true);
+        input.extend(&[0x82, 127, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert!(input.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_read_from_stream() {
+        let (mut client, mut server) = pair();
+        let large: Vec<u8> = (0..200000).map(|i| i as u8).collect();
+        let mut data = encode(large.clone(), true);
+        data.extend(encode(vec![1, 2, 3], true));
+        data.extend(encode(vec![4; 40], false));
+        let writer = thread::spawn(move || write_all(&mut client, &data));
+
+        let mut input = Input::new(64, true);
+        let mut frames = Vec::new();
+        while frames.len() < 3 {
+            let read = input.read_from(&mut server).unwrap();
+            assert!(read != Some(0), "The stream was closed early.");
+            while let Some((frame, _)) = input.next_frame().unwrap() {
+                frames.push(frame.into_data());
+            }
+            if read.is_none() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        writer.join().unwrap();
+
+        assert_eq!(frames, vec![large, vec![1, 2, 3], vec![4; 40]]);
+        assert!(input.is_empty());
+        assert_eq!(input.buf.len(), 64);
+    }
+}
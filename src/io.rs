@@ -1,5 +1,7 @@
+use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::borrow::Borrow;
+use std::sync::Arc;
 
 use mio;
 use mio::{
@@ -13,9 +15,17 @@ use mio::util::Slab;
 use url::Url;
 
 use communication::{Sender, Signal, Command};
+use handshake::ConnectOptions;
 use result::{Result, Error, Kind};
-use connection::Connection;
+use connection::{Connection, message_frames};
+use payload::Payload;
+use protocol::OpCode;
 use factory::Factory;
+use origin::OriginPolicy;
+#[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+use tls::{self, TlsServerConfig};
+#[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+use tls_config::TlsClientConfig;
 use super::Settings;
 
 pub const ALL: Token = Token(0);
@@ -62,22 +72,70 @@ pub struct Handler<F>
     connections: Slab<Conn<F>>,
     factory: F,
     settings: Settings,
+    origins: Arc<OriginPolicy>,
+    protocols: Arc<Vec<String>>,
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    tls: Option<TlsServerConfig>,
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    tls_client: Option<TlsClientConfig>,
+    // built from tls_client when the first wss connection is made
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    client_context: Option<Arc<tls::ClientContext>>,
     state: State,
 }
 
 impl<F> Handler<F>
     where F: Factory
 {
-    pub fn new(factory: F, settings: Settings) -> Handler<F> {
+    pub fn new(factory: F, settings: Settings, origins: Arc<OriginPolicy>, protocols: Arc<Vec<String>>) -> Handler<F> {
         Handler {
             listener: None,
             connections: Slab::new_starting_at(CONN_START, settings.max_connections),
             factory: factory,
             settings: settings,
+            origins: origins,
+            protocols: protocols,
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            tls: None,
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            tls_client: None,
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            client_context: None,
             state: State::Active,
         }
     }
 
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    pub fn with_tls(mut self, tls: TlsServerConfig) -> Handler<F> {
+        self.settings.encrypt_server = true;
+        self.tls = Some(tls);
+        self
+    }
+
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    fn reload_tls(&mut self, tls: TlsServerConfig) {
+        debug!("Replacing the TLS configuration for new connections.");
+        self.tls = Some(tls);
+    }
+
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    pub fn with_tls_client(mut self, config: TlsClientConfig) -> Handler<F> {
+        self.tls_client = Some(config);
+        self
+    }
+
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    fn client_context(&mut self) -> Result<Arc<tls::ClientContext>> {
+        if self.client_context.is_none() {
+            let context = match self.tls_client {
+                Some(ref config) => try!(tls::ClientContext::new(config)),
+                None => try!(tls::ClientContext::new(&TlsClientConfig::default())),
+            };
+            self.client_context = Some(Arc::new(context));
+        }
+        Ok(self.client_context.as_ref().unwrap().clone())
+    }
+
     pub fn listen(&mut self, eloop: &mut Loop<F>, addr: &SocketAddr) -> Result<&mut Handler<F>> {
 
         debug_assert!(
@@ -91,49 +149,14 @@ impl<F> Handler<F>
         Ok(self)
     }
 
-    #[cfg(all(not(windows), feature="ssl"))]
-    pub fn connect(&mut self, eloop: &mut Loop<F>, url: &Url) -> Result<()> {
-        let mut addresses = try!(url_to_addrs(url));
-        // note popping from the vector will most likely give us a tcpip v4 address
-        let addr = try!(addresses.pop().ok_or(
-            Error::new(
-                Kind::Internal,
-                format!("Unable to obtain any socket address for {}", url))));
-
-        let sock = try!(TcpStream::connect(&addr));
-        let factory = &mut self.factory;
-        let settings = self.settings;
-
-        let tok = try!(self.connections.insert_with(|tok| {
-            let handler = factory.client_connected(Sender::new(tok, eloop.channel()));
-            Connection::new(tok, sock, handler, settings)
-        }).ok_or(Error::new(Kind::Capacity, "Unable to add another connection to the event loop.")));
-
-        let conn = &mut self.connections[tok];
-
-        try!(conn.as_client(url, addresses));
-
-        if url.scheme == "wss" {
-            try!(conn.encrypt())
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.listener {
+            Some(ref listener) => listener.local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "The WebSocket is not listening for connections.")),
         }
-
-        eloop.register(
-            conn.socket(),
-            conn.token(),
-            conn.events(),
-            PollOpt::edge() | PollOpt::oneshot(),
-        ).map_err(Error::from).or_else(|err| {
-            error!("Encountered error while trying to build WebSocket connection: {}", err);
-            conn.error(err);
-            if settings.panic_on_new_connection {
-                panic!("Encountered error while trying to build WebSocket connection.");
-            }
-            Ok(())
-        })
     }
 
-    #[cfg(not(feature="ssl"))]
-    pub fn connect(&mut self, eloop: &mut Loop<F>, url: &Url) -> Result<()> {
+    pub fn connect(&mut self, eloop: &mut Loop<F>, url: &Url, options: &ConnectOptions) -> Result<()> {
         let mut addresses = try!(url_to_addrs(url));
         // note popping from the vector will most likely give us a tcpip v4 address
         let addr = try!(addresses.pop().ok_or(
@@ -141,53 +164,39 @@ impl<F> Handler<F>
                 Kind::Internal,
                 format!("Unable to obtain any socket address for {}", url))));
 
+        #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+        let client_tls = if url.scheme == "wss" {
+            Some((try!(self.client_context()), self.tls_client.is_some()))
+        } else {
+            None
+        };
+
         let sock = try!(TcpStream::connect(&addr));
         let factory = &mut self.factory;
         let settings = self.settings;
+        let origins = &self.origins;
+        let protocols = &self.protocols;
 
         let tok = try!(self.connections.insert_with(|tok| {
             let handler = factory.client_connected(Sender::new(tok, eloop.channel()));
-            Connection::new(tok, sock, handler, settings)
+            Connection::new(tok, sock, handler, settings, origins.clone(), protocols.clone())
         }).ok_or(Error::new(Kind::Capacity, "Unable to add another connection to the event loop.")));
 
         let conn = &mut self.connections[tok];
 
-        try!(conn.as_client(url, addresses));
+        try!(conn.as_client(url, addresses, options));
 
-        if url.scheme == "wss" {
-            return Err(Error::new(Kind::Protocol, "The ssl feature is not enabled. Please enable it to use wss urls."))
+        #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+        {
+            if let Some((context, configured)) = client_tls {
+                try!(conn.encrypt_client(context, configured))
+            }
         }
-
-        eloop.register(
-            conn.socket(),
-            conn.token(),
-            conn.events(),
-            PollOpt::edge() | PollOpt::oneshot(),
-        ).map_err(Error::from).or_else(|err| {
-            error!("Encountered error while trying to build WebSocket connection: {}", err);
-            conn.error(err);
-            if settings.panic_on_new_connection {
-                panic!("Encountered error while trying to build WebSocket connection.");
+        #[cfg(not(any(all(not(windows), feature="ssl"), feature="rustls")))]
+        {
+            if url.scheme == "wss" {
+                return Err(Error::new(Kind::Protocol, "The ssl feature is not enabled. Please enable it to use wss urls."))
             }
-            Ok(())
-        })
-    }
-
-    #[cfg(all(not(windows), feature="ssl"))]
-    pub fn accept(&mut self, eloop: &mut Loop<F>, sock: TcpStream) -> Result<()> {
-        let factory = &mut self.factory;
-        let settings = self.settings;
-
-        let tok = try!(self.connections.insert_with(|tok| {
-            let handler = factory.server_connected(Sender::new(tok, eloop.channel()));
-            Connection::new(tok, sock, handler, settings)
-        }).ok_or(Error::new(Kind::Capacity, "Unable to add another connection to the event loop.")));
-
-        let conn = &mut self.connections[tok];
-
-        try!(conn.as_server());
-        if settings.encrypt_server {
-            try!(conn.encrypt())
         }
 
         eloop.register(
@@ -205,21 +214,32 @@ impl<F> Handler<F>
         })
     }
 
-    #[cfg(not(feature="ssl"))]
     pub fn accept(&mut self, eloop: &mut Loop<F>, sock: TcpStream) -> Result<()> {
         let factory = &mut self.factory;
         let settings = self.settings;
+        #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+        let server_tls = &self.tls;
+        let origins = &self.origins;
+        let protocols = &self.protocols;
 
         let tok = try!(self.connections.insert_with(|tok| {
             let handler = factory.server_connected(Sender::new(tok, eloop.channel()));
-            Connection::new(tok, sock, handler, settings)
+            Connection::new(tok, sock, handler, settings, origins.clone(), protocols.clone())
         }).ok_or(Error::new(Kind::Capacity, "Unable to add another connection to the event loop.")));
 
         let conn = &mut self.connections[tok];
 
         try!(conn.as_server());
-        if settings.encrypt_server {
-            return Err(Error::new(Kind::Protocol, "The ssl feature is not enabled. Please enable it to use wss urls."))
+        if settings.detect_tls {
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            conn.detect_tls(server_tls.clone());
+            #[cfg(not(any(all(not(windows), feature="ssl"), feature="rustls")))]
+            return Err(Error::new(Kind::Protocol, "The ssl feature is not enabled. Please enable it to use wss urls."));
+        } else if settings.encrypt_server {
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            try!(conn.encrypt(try!(tls::server_session(server_tls))));
+            #[cfg(not(any(all(not(windows), feature="ssl"), feature="rustls")))]
+            return Err(Error::new(Kind::Protocol, "The ssl feature is not enabled. Please enable it to use wss urls."));
         }
 
         eloop.register(
@@ -248,6 +268,22 @@ impl<F> Handler<F>
         )))
     }
 
+    fn broadcast(&mut self, opcode: OpCode, data: Payload, dead: &mut Vec<(Token, Error)>) {
+        // the frames are formatted once and shared by every connection, unless a connection
+        // needs to mask them or its handler changes them
+        let mut frames = message_frames(opcode, data, self.settings.fragment_size);
+        if self.connections.count() > 1 {
+            for frame in frames.iter_mut() {
+                frame.encode();
+            }
+        }
+        for conn in self.connections.iter_mut() {
+            if let Err(err) = conn.send_frames(frames.clone()) {
+                dead.push((conn.token(), err))
+            }
+        }
+    }
+
     fn shutdown(&mut self, eloop: &mut Loop<F>) {
         debug!("Received shutdown signal. WebSocket is attempting to shut down.");
         for conn in self.connections.iter_mut() {
@@ -402,8 +438,17 @@ impl<F> mio::Handler for Handler <F>
                 match cmd.into_signal() {
                     Signal::Message(msg) => {
                         debug!("Broadcasting message: {:?}", msg);
+                        let opcode = msg.opcode();
+                        self.broadcast(opcode, Payload::new(msg.into_data()), &mut dead)
+                    }
+                    Signal::Shared(opcode, data) => {
+                        debug!("Broadcasting shared message of {} bytes.", data.len());
+                        self.broadcast(opcode, data, &mut dead)
+                    }
+                    Signal::Frame(frame) => {
+                        debug!("Broadcasting frame: {}", frame);
                         for conn in self.connections.iter_mut() {
-                            if let Err(err) = conn.send_message(msg.clone()) {
+                            if let Err(err) = conn.send_frame(frame.clone()) {
                                 dead.push((conn.token(), err))
                             }
                         }
@@ -432,8 +477,8 @@ impl<F> mio::Handler for Handler <F>
                             }
                         }
                     }
-                    Signal::Connect(ref url) => {
-                        if let Err(err) = self.connect(eloop, url) {
+                    Signal::Connect(ref url, ref options) => {
+                        if let Err(err) = self.connect(eloop, url, options) {
                             if self.settings.panic_on_new_connection {
                                 panic!("Unable to establish connection to {}: {:?}", url, err);
                             }
@@ -441,6 +486,8 @@ impl<F> mio::Handler for Handler <F>
                         }
                         return
                     }
+                    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+                    Signal::Tls(config) => return self.reload_tls(config),
                     Signal::Shutdown => self.shutdown(eloop),
                 }
 
@@ -465,6 +512,24 @@ impl<F> mio::Handler for Handler <F>
                             debug!("Connection disconnected while a message was waiting in the queue.")
                         }
                     }
+                    Signal::Shared(opcode, data) => {
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if let Err(err) = conn.send_shared(opcode, data) {
+                                conn.error(err)
+                            }
+                        } else {
+                            debug!("Connection disconnected while a message was waiting in the queue.")
+                        }
+                    }
+                    Signal::Frame(frame) => {
+                        if let Some(conn) = self.connections.get_mut(token) {
+                            if let Err(err) = conn.send_frame(frame) {
+                                conn.error(err)
+                            }
+                        } else {
+                            debug!("Connection disconnected while a frame was waiting in the queue.")
+                        }
+                    }
                     Signal::Close(code, reason) => {
                         if let Some(conn) = self.connections.get_mut(token) {
                             if let Err(err) = conn.send_close(code, reason) {
@@ -492,8 +557,8 @@ impl<F> mio::Handler for Handler <F>
                             debug!("Connection disconnected while pong signal was waiting in the queue.")
                         }
                     }
-                    Signal::Connect(ref url) => {
-                        if let Err(err) = self.connect(eloop, url) {
+                    Signal::Connect(ref url, ref options) => {
+                        if let Err(err) = self.connect(eloop, url, options) {
                             if let Some(conn) = self.connections.get_mut(token) {
                                 conn.error(err)
                             } else {
@@ -502,6 +567,8 @@ impl<F> mio::Handler for Handler <F>
                         }
                         return
                     }
+                    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+                    Signal::Tls(config) => return self.reload_tls(config),
                     Signal::Shutdown => self.shutdown(eloop),
                 }
 
@@ -0,0 +1,223 @@
+//! Typed JSON messages.
+//!
+//! Wrap a handler in `Json` to receive incoming messages as a deserialized type rather than as raw
+//! `Message`s. Messages that cannot be decoded close the connection, so the handler only ever
+//! sees well-formed values. Use `Sender::send_json` to send values the other way.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! extern crate serde;
+//! #[macro_use] extern crate serde_derive;
+//! extern crate ws;
+//!
+//! use ws::json::{Json, JsonHandler};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Point {
+//!     x: i32,
+//!     y: i32,
+//! }
+//!
+//! struct Mirror {
+//!     out: ws::Sender,
+//! }
+//!
+//! impl ws::Handler for Mirror {}
+//!
+//! impl JsonHandler for Mirror {
+//!     type Message = Point;
+//!
+//!     fn on_json(&mut self, point: Point) -> ws::Result<()> {
+//!         self.out.send_json(&Point { x: point.y, y: point.x })
+//!     }
+//! }
+//!
+//! # fn main() {
+//! ws::listen("127.0.0.1:3012", |out: ws::Sender| {
+//!     Json::new(out.clone(), Mirror { out: out })
+//! }).unwrap();
+//! # }
+//! ```
+use std::result::Result as StdResult;
+
+use url;
+use serde::de::DeserializeOwned;
+use serde_json;
+#[cfg(all(not(windows), feature="ssl"))]
+use openssl::ssl::Ssl;
+
+use communication::Sender;
+use frame::Frame;
+use handler::Handler;
+use handshake::{Handshake, Request, Response};
+use message::Message;
+use protocol::CloseCode;
+use result::{Result, Error};
+
+// The payload of a close frame is limited to 125 bytes, two of which hold the code.
+const MAX_REASON: usize = 123;
+
+/// A handler for messages that have already been decoded from JSON.
+pub trait JsonHandler {
+    /// The type that incoming messages are decoded into.
+    type Message: DeserializeOwned;
+
+    /// Called on incoming messages that were successfully decoded.
+    fn on_json(&mut self, msg: Self::Message) -> Result<()>;
+}
+
+/// A Handler that decodes incoming text messages as JSON before passing them on.
+///
+/// Binary messages close the connection with `CloseCode::Unsupported`, and text that does not
+/// decode as `H::Message` closes it with `CloseCode::Invalid`. The serde error is sent as the
+/// reason. All other events are passed straight through to the inner handler.
+pub struct Json<H> {
+    out: Sender,
+    inner: H,
+}
+
+impl<H> Json<H>
+    where H: Handler + JsonHandler
+{
+    /// Wrap a handler. The Sender is used to close the connection when a message can't be decoded.
+    pub fn new(out: Sender, inner: H) -> Json<H> {
+        Json {
+            out: out,
+            inner: inner,
+        }
+    }
+
+    /// A reference to the inner handler.
+    pub fn get_ref(&self) -> &H {
+        &self.inner
+    }
+
+    /// A mutable reference to the inner handler.
+    pub fn get_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+}
+
+fn decode<T>(msg: Message) -> StdResult<T, (CloseCode, String)>
+    where T: DeserializeOwned
+{
+    match msg {
+        Message::Text(text) => serde_json::from_str(&text).map_err(|err| {
+            (CloseCode::Invalid, truncate(format!("Unable to decode JSON: {}", err)))
+        }),
+        Message::Binary(_) => Err((CloseCode::Unsupported, "Expected a JSON text message.".into())),
+    }
+}
+
+fn truncate(mut reason: String) -> String {
+    if reason.len() > MAX_REASON {
+        let mut end = MAX_REASON;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+    }
+    reason
+}
+
+impl<H> Handler for Json<H>
+    where H: Handler + JsonHandler
+{
+    #[inline]
+    fn on_shutdown(&mut self) {
+        self.inner.on_shutdown()
+    }
+
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.inner.on_open(shake)
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        match decode(msg) {
+            Ok(value) => self.inner.on_json(value),
+            Err((code, reason)) => {
+                debug!("Closing connection due to undecodable message: {}", reason);
+                self.out.close_with_reason(code, reason)
+            }
+        }
+    }
+
+    #[inline]
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.inner.on_close(code, reason)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: Error) {
+        self.inner.on_error(err)
+    }
+
+    #[inline]
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        self.inner.on_request(req)
+    }
+
+    #[inline]
+    fn on_http_request(&mut self, req: &Request) -> Result<Response> {
+        self.inner.on_http_request(req)
+    }
+
+    #[inline]
+    fn on_response(&mut self, res: &Response) -> Result<()> {
+        self.inner.on_response(res)
+    }
+
+    #[inline]
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.inner.on_frame(frame)
+    }
+
+    #[inline]
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.inner.on_send_frame(frame)
+    }
+
+    #[inline]
+    fn on_drain(&mut self) {
+        self.inner.on_drain()
+    }
+
+    #[inline]
+    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+        self.inner.build_request(url)
+    }
+
+    #[inline]
+    #[cfg(all(not(windows), feature="ssl"))]
+    fn build_ssl(&mut self) -> Result<Option<Ssl>> {
+        self.inner.build_ssl()
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_decode() {
+        let map: HashMap<String, u32> = decode(Message::text(r#"{"a": 1}"#)).unwrap();
+        assert_eq!(map["a"], 1);
+
+        let (code, _) = decode::<HashMap<String, u32>>(Message::text(r#"{"a": "b"}"#)).unwrap_err();
+        assert_eq!(code, CloseCode::Invalid);
+
+        let (code, _) = decode::<HashMap<String, u32>>(Message::binary(vec![123, 125])).unwrap_err();
+        assert_eq!(code, CloseCode::Unsupported);
+    }
+
+    #[test]
+    fn test_truncate_reason() {
+        let reason = truncate("é".repeat(100));
+        assert!(reason.len() <= MAX_REASON);
+        assert_eq!(reason.chars().count(), MAX_REASON / 2);
+        assert_eq!(truncate("short".into()), "short");
+    }
+}
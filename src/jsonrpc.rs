@@ -0,0 +1,668 @@
+//! JSON-RPC 2.0 over WebSocket connections.
+//!
+//! Servers register methods in a `Methods` table and answer requests with a `Server` handler.
+//! Clients wrap their connection with `client`, which returns a `ClientHandler` for the event
+//! loop and a `Client` for making calls from other threads. Calls block until the matching
+//! response arrives or the timeout elapses.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! extern crate serde_json;
+//! extern crate ws;
+//!
+//! use std::sync::Arc;
+//! use ws::jsonrpc::{Methods, RpcError, Server};
+//!
+//! # fn main() {
+//! let mut methods = Methods::new();
+//! methods.add("add", |params| {
+//!     let nums: Vec<i64> = try!(serde_json::from_value(params).map_err(RpcError::invalid_params));
+//!     Ok(nums.iter().sum::<i64>().into())
+//! });
+//! let methods = Arc::new(methods);
+//!
+//! ws::listen("127.0.0.1:3012", |out| Server::new(out, methods.clone())).unwrap();
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use serde_json;
+use serde_json::Value;
+
+use communication::Sender;
+use handler::Handler;
+use message::Message;
+use protocol::CloseCode;
+use result::{Result, Error, Kind};
+
+/// Invalid JSON was received.
+pub const PARSE_ERROR: i64 = -32700;
+/// The JSON sent is not a valid Request object.
+pub const INVALID_REQUEST: i64 = -32600;
+/// The method does not exist or is not available.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// Invalid method parameters.
+pub const INVALID_PARAMS: i64 = -32602;
+/// Internal JSON-RPC error.
+pub const INTERNAL_ERROR: i64 = -32603;
+
+const VERSION: &'static str = "2.0";
+
+/// A JSON-RPC error object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    /// The error code. Codes from -32768 to -32000 are reserved for the errors defined by the
+    /// specification, such as `METHOD_NOT_FOUND`.
+    pub code: i64,
+    /// A short description of the error.
+    pub message: String,
+    /// Additional information about the error, defined by the server.
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+
+    /// Create an error object without data.
+    pub fn new<S>(code: i64, message: S) -> RpcError
+        where S: Into<String>
+    {
+        RpcError {
+            code: code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    /// Attach additional information about the error.
+    pub fn with_data(mut self, data: Value) -> RpcError {
+        self.data = Some(data);
+        self
+    }
+
+    /// A parse error, for text that is not valid JSON.
+    pub fn parse_error() -> RpcError {
+        RpcError::new(PARSE_ERROR, "Parse error")
+    }
+
+    /// An invalid request error, for JSON that is not a valid request object.
+    pub fn invalid_request() -> RpcError {
+        RpcError::new(INVALID_REQUEST, "Invalid Request")
+    }
+
+    /// A method not found error.
+    pub fn method_not_found() -> RpcError {
+        RpcError::new(METHOD_NOT_FOUND, "Method not found")
+    }
+
+    /// An invalid params error, with the description of the cause as data.
+    pub fn invalid_params<E>(err: E) -> RpcError
+        where E: fmt::Display
+    {
+        RpcError::new(INVALID_PARAMS, "Invalid params").with_data(Value::String(err.to_string()))
+    }
+
+    /// An internal error.
+    pub fn internal_error() -> RpcError {
+        RpcError::new(INTERNAL_ERROR, "Internal error")
+    }
+
+    fn to_value(&self) -> Value {
+        let mut obj = serde_json::Map::new();
+        obj.insert("code".into(), self.code.into());
+        obj.insert("message".into(), self.message.clone().into());
+        if let Some(ref data) = self.data {
+            obj.insert("data".into(), data.clone());
+        }
+        Value::Object(obj)
+    }
+
+    fn from_value(value: &Value) -> Option<RpcError> {
+        match (value.get("code").and_then(Value::as_i64), value.get("message").and_then(Value::as_str)) {
+            (Some(code), Some(message)) => Some(RpcError {
+                code: code,
+                message: message.into(),
+                data: value.get("data").cloned(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl StdError for RpcError {
+
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// The outcome of a call: the result, or the error object sent by the server.
+pub type Outcome = StdResult<Value, RpcError>;
+
+fn response(id: Value, outcome: Outcome) -> Value {
+    let mut obj = serde_json::Map::new();
+    obj.insert("jsonrpc".into(), VERSION.into());
+    match outcome {
+        Ok(result) => obj.insert("result".into(), result),
+        Err(err) => obj.insert("error".into(), err.to_value()),
+    };
+    obj.insert("id".into(), id);
+    Value::Object(obj)
+}
+
+fn request(method: &str, params: Value, id: Option<u64>) -> Value {
+    let mut obj = serde_json::Map::new();
+    obj.insert("jsonrpc".into(), VERSION.into());
+    obj.insert("method".into(), method.into());
+    if !params.is_null() {
+        obj.insert("params".into(), params);
+    }
+    if let Some(id) = id {
+        obj.insert("id".into(), id.into());
+    }
+    Value::Object(obj)
+}
+
+/// A method that can be called remotely. It receives the params of the request, or
+/// `Value::Null` if there were none. Methods may be called from any thread, so that a table of
+/// them can be shared between the connections of a server.
+pub type Method = Box<Fn(Value) -> Outcome + Send + Sync>;
+
+/// A table of the methods offered by a server.
+pub struct Methods {
+    methods: HashMap<String, Method>,
+}
+
+impl Methods {
+
+    pub fn new() -> Methods {
+        Methods {
+            methods: HashMap::new(),
+        }
+    }
+
+    /// Register a method, replacing any previous method with the same name.
+    pub fn add<S, F>(&mut self, name: S, method: F) -> &mut Methods
+        where S: Into<String>, F: Fn(Value) -> Outcome + Send + Sync + 'static
+    {
+        self.methods.insert(name.into(), Box::new(method));
+        self
+    }
+
+    /// Handle the text of a request or batch, returning the text of the response if there is
+    /// one. Notifications, and batches made only of notifications, have no response.
+    pub fn handle(&self, text: &str) -> Option<String> {
+        let response = match serde_json::from_str::<Value>(text) {
+            Ok(Value::Array(ref batch)) if batch.is_empty() => {
+                Some(response(Value::Null, Err(RpcError::invalid_request())))
+            }
+            Ok(Value::Array(batch)) => {
+                let responses: Vec<Value> = batch.into_iter().filter_map(|req| self.call(req)).collect();
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(responses))
+                }
+            }
+            Ok(req) => self.call(req),
+            Err(_) => Some(response(Value::Null, Err(RpcError::parse_error()))),
+        };
+        response.map(|res| res.to_string())
+    }
+
+    fn call(&self, req: Value) -> Option<Value> {
+        let mut req = match req {
+            Value::Object(req) => req,
+            _ => return Some(response(Value::Null, Err(RpcError::invalid_request()))),
+        };
+
+        let id = req.remove("id");
+        let valid_id = match id {
+            None | Some(Value::Null) | Some(Value::Number(_)) | Some(Value::String(_)) => true,
+            _ => false,
+        };
+        let params = req.remove("params").unwrap_or(Value::Null);
+        let valid = valid_id &&
+            req.get("jsonrpc").and_then(Value::as_str) == Some(VERSION) &&
+            (params.is_null() || params.is_array() || params.is_object());
+
+        let method = match req.get("method").and_then(Value::as_str) {
+            Some(method) if valid => method,
+            _ => {
+                let id = if valid_id { id.unwrap_or(Value::Null) } else { Value::Null };
+                return Some(response(id, Err(RpcError::invalid_request())))
+            }
+        };
+
+        let outcome = match self.methods.get(method) {
+            Some(method) => method(params),
+            None => Err(RpcError::method_not_found()),
+        };
+        id.map(|id| response(id, outcome))
+    }
+}
+
+/// A Handler that answers JSON-RPC requests with a table of methods.
+pub struct Server {
+    out: Sender,
+    methods: Arc<Methods>,
+}
+
+impl Server {
+
+    pub fn new(out: Sender, methods: Arc<Methods>) -> Server {
+        Server {
+            out: out,
+            methods: methods,
+        }
+    }
+}
+
+impl Handler for Server {
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Binary(_) => {
+                return self.out.close_with_reason(
+                    CloseCode::Unsupported, "Expected a JSON-RPC text message.")
+            }
+        };
+        match self.methods.handle(&text) {
+            Some(res) => self.out.send(res),
+            None => Ok(()),
+        }
+    }
+}
+
+type Pending = Arc<Mutex<HashMap<u64, mpsc::Sender<Outcome>>>>;
+
+/// A handle for calling methods on a JSON-RPC server. Clones share the same connection.
+///
+/// The methods of a Client block the calling thread until a response arrives, so they must not be
+/// called from the handler of the event loop that runs the connection.
+#[derive(Clone)]
+pub struct Client {
+    out: Sender,
+    pending: Pending,
+    next_id: Arc<Mutex<u64>>,
+    timeout: Duration,
+}
+
+/// The Handler that passes responses from the server to the waiting `Client` calls.
+pub struct ClientHandler {
+    pending: Pending,
+}
+
+/// Create a JSON-RPC client for a connection. The ClientHandler must be returned from the
+/// factory as the handler of the connection, and the Client can be sent to other threads.
+pub fn client(out: Sender) -> (Client, ClientHandler) {
+    let pending = Arc::new(Mutex::new(HashMap::new()));
+    let client = Client {
+        out: out,
+        pending: pending.clone(),
+        next_id: Arc::new(Mutex::new(1)),
+        timeout: Duration::from_secs(30),
+    };
+    (client, ClientHandler { pending: pending })
+}
+
+fn timed_out() -> Error {
+    Error::from(io::Error::new(io::ErrorKind::TimedOut, "The JSON-RPC call timed out."))
+}
+
+fn disconnected() -> Error {
+    Error::new(Kind::Internal, "The connection closed before the JSON-RPC call completed.")
+}
+
+impl Client {
+
+    /// Set how long calls wait for a response. The default is 30 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Client {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Call a method and wait for its result. Pass `Value::Null` for a call without params.
+    ///
+    /// The outer Result fails if the call could not be sent or timed out, and the inner Result
+    /// holds the error object if the server answered with one.
+    pub fn call(&self, method: &str, params: Value) -> Result<Outcome> {
+        let (id, rx) = self.register();
+        if let Err(err) = self.out.send(request(method, params, Some(id)).to_string()) {
+            self.forget(id);
+            return Err(err)
+        }
+        self.wait(id, &rx)
+    }
+
+    /// Send a notification, which the server does not answer.
+    pub fn notify(&self, method: &str, params: Value) -> Result<()> {
+        self.out.send(request(method, params, None).to_string())
+    }
+
+    /// Send a batch of calls and notifications, and wait for all of the results. The outcomes of
+    /// the calls are returned in the order they were added to the batch.
+    pub fn batch(&self, batch: Batch) -> Result<Vec<Outcome>> {
+        let mut requests = Vec::with_capacity(batch.requests.len());
+        let mut waiting = Vec::new();
+        for (method, params, is_call) in batch.requests {
+            if is_call {
+                let (id, rx) = self.register();
+                requests.push(request(&method, params, Some(id)));
+                waiting.push((id, rx));
+            } else {
+                requests.push(request(&method, params, None));
+            }
+        }
+
+        if let Err(err) = self.out.send(Value::Array(requests).to_string()) {
+            for &(id, _) in waiting.iter() {
+                self.forget(id);
+            }
+            return Err(err)
+        }
+
+        let mut outcomes = Vec::with_capacity(waiting.len());
+        for (i, &(id, ref rx)) in waiting.iter().enumerate() {
+            match self.wait(id, rx) {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(err) => {
+                    for &(id, _) in waiting[i..].iter() {
+                        self.forget(id);
+                    }
+                    return Err(err)
+                }
+            }
+        }
+        Ok(outcomes)
+    }
+
+    fn register(&self) -> (u64, mpsc::Receiver<Outcome>) {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            let id = *next_id;
+            *next_id += 1;
+            id
+        };
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        (id, rx)
+    }
+
+    fn forget(&self, id: u64) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    fn wait(&self, id: u64, rx: &mpsc::Receiver<Outcome>) -> Result<Outcome> {
+        match rx.recv_timeout(self.timeout) {
+            Ok(outcome) => Ok(outcome),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.forget(id);
+                Err(timed_out())
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(disconnected()),
+        }
+    }
+}
+
+/// A set of calls and notifications to be sent together with `Client::batch`.
+pub struct Batch {
+    requests: Vec<(String, Value, bool)>,
+}
+
+impl Batch {
+
+    pub fn new() -> Batch {
+        Batch {
+            requests: Vec::new(),
+        }
+    }
+
+    /// Add a call, whose outcome will be returned by `Client::batch`.
+    pub fn call<S>(&mut self, method: S, params: Value) -> &mut Batch
+        where S: Into<String>
+    {
+        self.requests.push((method.into(), params, true));
+        self
+    }
+
+    /// Add a notification.
+    pub fn notify<S>(&mut self, method: S, params: Value) -> &mut Batch
+        where S: Into<String>
+    {
+        self.requests.push((method.into(), params, false));
+        self
+    }
+}
+
+impl ClientHandler {
+
+    fn resolve(&self, res: Value) {
+        let id = match res.get("id").and_then(Value::as_u64) {
+            Some(id) => id,
+            None => {
+                // errors about requests the server could not read have a null id
+                debug!("Discarding JSON-RPC response without a known id: {}", res);
+                return
+            }
+        };
+        let outcome = match (res.get("result"), res.get("error").and_then(RpcError::from_value)) {
+            (_, Some(err)) => Err(err),
+            (Some(result), None) => Ok(result.clone()),
+            (None, None) => Err(RpcError::invalid_request().with_data(res.clone())),
+        };
+        if let Some(tx) = self.pending.lock().unwrap().remove(&id) {
+            let _ = tx.send(outcome);
+        }
+    }
+}
+
+impl Handler for ClientHandler {
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let res: Value = match msg {
+            Message::Text(ref text) => try!(serde_json::from_str(text).map_err(|err| {
+                Error::new(Kind::Protocol, format!("Received an invalid JSON-RPC response: {}", err))
+            })),
+            Message::Binary(_) => {
+                return Err(Error::new(Kind::Protocol, "Expected a JSON-RPC text message."))
+            }
+        };
+        match res {
+            Value::Array(batch) => for res in batch {
+                self.resolve(res)
+            },
+            res => self.resolve(res),
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        // dropping the senders wakes up the waiting calls
+        self.pending.lock().unwrap().clear();
+    }
+
+    fn on_error(&mut self, err: Error) {
+        error!("JSON-RPC client encountered an error: {:?}", err);
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+impl Drop for ClientHandler {
+
+    // The Clients share the pending calls, so a handler that is dropped without being closed,
+    // such as when the connection fails to open, must still wake them up.
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::thread;
+    use mio;
+    use serde_json;
+    use serde_json::Value;
+    use communication::Command;
+
+    struct S;
+
+    impl mio::Handler for S {
+        type Message = Command;
+        type Timeout = ();
+    }
+
+    fn methods() -> Methods {
+        let mut methods = Methods::new();
+        methods
+            .add("subtract", |params| {
+                let nums: Vec<i64> = try!(serde_json::from_value(params).map_err(RpcError::invalid_params));
+                Ok((nums[0] - nums[1]).into())
+            })
+            .add("update", |_| Ok(Value::Null));
+        methods
+    }
+
+    fn handle(methods: &Methods, text: &str) -> Option<Value> {
+        methods.handle(text).map(|res| serde_json::from_str(&res).unwrap())
+    }
+
+    #[test]
+    fn test_call() {
+        let res = handle(&methods(), r#"{"jsonrpc": "2.0", "method": "subtract", "params": [42, 23], "id": 1}"#);
+        assert_eq!(res.unwrap(), json(r#"{"jsonrpc": "2.0", "result": 19, "id": 1}"#));
+    }
+
+    #[test]
+    fn test_notification() {
+        assert!(handle(&methods(), r#"{"jsonrpc": "2.0", "method": "update", "params": [1]}"#).is_none());
+        assert!(handle(&methods(), r#"{"jsonrpc": "2.0", "method": "missing"}"#).is_none());
+    }
+
+    #[test]
+    fn test_errors() {
+        let methods = methods();
+        let code = |text: &str| handle(&methods, text).unwrap()["error"]["code"].as_i64().unwrap();
+
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "foobar, "params": "bar", "baz]"#), PARSE_ERROR);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": 1, "params": "bar"}"#), INVALID_REQUEST);
+        assert_eq!(code(r#"[]"#), INVALID_REQUEST);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "foobar", "id": "1"}"#), METHOD_NOT_FOUND);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "subtract", "params": {}, "id": 2}"#), INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_batch() {
+        let res = handle(&methods(), r#"[
+            {"jsonrpc": "2.0", "method": "subtract", "params": [3, 1], "id": "1"},
+            {"jsonrpc": "2.0", "method": "update", "params": [7]},
+            1,
+            {"jsonrpc": "2.0", "method": "subtract", "params": [9, 8], "id": 2}
+        ]"#).unwrap();
+        let res = res.as_array().unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0], json(r#"{"jsonrpc": "2.0", "result": 2, "id": "1"}"#));
+        assert_eq!(res[1]["error"]["code"].as_i64(), Some(INVALID_REQUEST));
+        assert_eq!(res[2], json(r#"{"jsonrpc": "2.0", "result": 1, "id": 2}"#));
+
+        assert!(handle(&methods(), r#"[{"jsonrpc": "2.0", "method": "update"}]"#).is_none());
+    }
+
+    #[test]
+    fn test_error_object() {
+        let err = RpcError::invalid_params("expected an array");
+        assert_eq!(RpcError::from_value(&err.to_value()), Some(err));
+    }
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    #[test]
+    fn test_methods_are_shareable() {
+        let methods = Arc::new(methods());
+        assert_send_sync(&methods);
+        let shared = methods.clone();
+        let res = thread::spawn(move || shared.handle(r#"{"jsonrpc": "2.0", "method": "update", "id": 1}"#));
+        assert!(res.join().unwrap().is_some());
+    }
+
+    // Wait until the calls with these ids have been sent.
+    fn wait_for_call(handler: &ClientHandler, ids: &[u64]) {
+        while ids.iter().any(|id| !handler.pending.lock().unwrap().contains_key(id)) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_client_correlation() {
+        let event_loop = mio::EventLoop::<S>::new().unwrap();
+        let (client, mut handler) = client(Sender::new(mio::Token(1), event_loop.channel()));
+
+        let first = client.clone();
+        let first = thread::spawn(move || first.call("first", Value::Null));
+        wait_for_call(&handler, &[1]);
+        let second = client.clone();
+        let second = thread::spawn(move || second.call("second", Value::Null));
+        wait_for_call(&handler, &[1, 2]);
+
+        // answer out of order, with an unknown id in between
+        handler.on_message(Message::text(r#"{"jsonrpc": "2.0", "result": "two", "id": 2}"#)).unwrap();
+        handler.on_message(Message::text(r#"{"jsonrpc": "2.0", "result": "none", "id": 7}"#)).unwrap();
+        handler.on_message(Message::text(
+            r#"{"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found"}, "id": 1}"#)).unwrap();
+
+        assert_eq!(second.join().unwrap().unwrap(), Ok(Value::String("two".into())));
+        assert_eq!(first.join().unwrap().unwrap(), Err(RpcError::method_not_found()));
+        assert!(handler.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_client_timeout() {
+        let event_loop = mio::EventLoop::<S>::new().unwrap();
+        let (mut client, mut handler) = client(Sender::new(mio::Token(1), event_loop.channel()));
+        client.set_timeout(Duration::from_millis(50));
+
+        let err = client.call("slow", Value::Null).unwrap_err();
+        match err.kind {
+            Kind::Io(ref err) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            _ => panic!("Expected a timeout, got {:?}", err),
+        }
+        assert!(handler.pending.lock().unwrap().is_empty());
+
+        // a response that arrives too late is discarded
+        handler.on_message(Message::text(r#"{"jsonrpc": "2.0", "result": 1, "id": 1}"#)).unwrap();
+    }
+
+    #[test]
+    fn test_client_handler_dropped() {
+        let event_loop = mio::EventLoop::<S>::new().unwrap();
+        let (client, handler) = client(Sender::new(mio::Token(1), event_loop.channel()));
+
+        let call = thread::spawn(move || client.call("lost", Value::Null));
+        wait_for_call(&handler, &[1]);
+        drop(handler);
+
+        // the call fails at once instead of waiting for the timeout
+        let err = call.join().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), disconnected().to_string());
+    }
+
+    fn json(text: &str) -> Value {
+        serde_json::from_str(text).unwrap()
+    }
+}
@@ -253,6 +253,11 @@ extern crate sha1;
 extern crate rand;
 extern crate url;
 #[cfg(all(not(windows), feature="ssl"))] extern crate openssl;
+#[cfg(feature="futures")] extern crate futures;
+#[cfg(feature="rustls")] extern crate rustls;
+#[cfg(feature="serde")] extern crate serde;
+#[cfg(feature="serde")] extern crate serde_json;
+#[cfg(feature="rustls")] extern crate webpki_roots;
 #[macro_use] extern crate log;
 
 mod result;
@@ -260,12 +265,25 @@ mod connection;
 mod handler;
 mod factory;
 mod frame;
+mod payload;
+mod output;
+mod input;
+mod utf8;
 mod message;
 mod handshake;
 mod protocol;
 mod communication;
 mod io;
 mod stream;
+mod origin;
+pub mod sync;
+#[cfg(feature="futures")] pub mod futures_adapter;
+#[cfg(feature="serde")] pub mod json;
+#[cfg(feature="serde")] pub mod jsonrpc;
+// rustls takes precedence when both TLS features are enabled
+#[cfg(all(not(windows), feature="ssl", not(feature="rustls")))] mod tls;
+#[cfg(feature="rustls")] #[path="rustls_tls.rs"] mod tls;
+#[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))] mod tls_config;
 
 pub use factory::Factory;
 pub use handler::Handler;
@@ -275,14 +293,19 @@ pub use result::Kind as ErrorKind;
 pub use message::Message;
 pub use communication::Sender;
 pub use frame::Frame;
+pub use payload::Payload;
 pub use protocol::{CloseCode, OpCode};
-pub use handshake::{Handshake, Request, Response};
+pub use origin::OriginPolicy;
+#[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))] pub use tls::TlsServerConfig;
+#[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))] pub use tls_config::{TlsClientConfig, TlsVersion};
+pub use handshake::{Handshake, Request, Response, Authorization, ConnectOptions, TlsInfo};
 
 use std::fmt;
 use std::default::Default;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use mio::EventLoopConfig;
 use std::borrow::Borrow;
+use std::sync::Arc;
 
 /// A utility function for setting up a WebSocket server.
 ///
@@ -379,19 +402,20 @@ pub struct Settings {
     /// The maximum length of outgoing frames. Messages longer than this will be fragmented.
     /// Default: 65,535
     pub fragment_size: usize,
-    /// The size of the incoming buffer. A larger buffer uses more memory but will allow for fewer
-    /// reallocations.
+    /// The size of the incoming buffer. Frames that do not fit in the buffer are read into a
+    /// buffer of their own, so a larger buffer only means fewer reads for many small frames.
     /// Default: 2048
     pub in_buffer_capacity: usize,
-    /// Whether to reallocate the incoming buffer when `in_buffer_capacity` is reached. If this is
+    /// Whether to accept frames with payloads larger than `in_buffer_capacity`. If this is
     /// false, a Capacity error will be triggered instead.
     /// Default: true
     pub in_buffer_grow: bool,
-    /// The size of the outgoing buffer. A larger buffer uses more memory but will allow for fewer
-    /// reallocations.
+    /// The number of bytes that may be waiting to be written to a connection when
+    /// `out_buffer_grow` is false. Outgoing frames are queued without being copied, so this is only
+    /// a limit and is not allocated up front.
     /// Default: 2048
     pub out_buffer_capacity: usize,
-    /// Whether to reallocate the incoming buffer when `out_buffer_capacity` is reached. If this is
+    /// Whether to allow more than `out_buffer_capacity` bytes to wait to be written. If this is
     /// false, a Capacity error will be triggered instead.
     /// Default: true
     pub out_buffer_grow: bool,
@@ -430,16 +454,31 @@ pub struct Settings {
     /// requirement that handshakes begin with a GET method, set this to true.
     /// Default: false
     pub method_strict: bool,
+    /// When a server supports subprotocols set with `Builder::with_protocols`, the first one
+    /// offered by the client is selected automatically. Clients that offer none of them are
+    /// accepted without a subprotocol unless this is set to true, in which case the handshake
+    /// fails with an HTTP 400 response.
+    /// Default: false
+    pub protocol_strict: bool,
     /// Indicate whether server connections should use ssl encryption when accepting connections.
     /// Setting this to true means that clients should use the `wss` scheme to connect to this
-    /// server. Note that using this flag will in general necessitate overriding the
-    /// `Handler::build_ssl` method in order to provide the details of the ssl context. It may be
-    /// simpler for most users to use a reverse proxy such as nginx to provide server side
-    /// encryption.
+    /// server. The details of the ssl context are usually provided with `Builder::with_tls`,
+    /// which also sets this flag. Otherwise, using this flag will necessitate overriding the
+    /// `Handler::build_ssl` method. It may be simpler for some users to use a reverse proxy such
+    /// as nginx to provide server side encryption. With the `rustls` feature, `Builder::with_tls`
+    /// is the only way to provide the certificate.
     ///
-    /// Note: This setting is not supported on Windows.
+    /// Note: This setting is not supported on Windows unless the `rustls` feature is used.
     /// Default: false
     pub encrypt_server: bool,
+    /// Indicate whether servers should accept both `ws` and `wss` connections on the same port.
+    /// The first byte sent by each client is examined, and the connection is encrypted if it
+    /// starts a TLS handshake, using the same configuration as `encrypt_server`. This is useful
+    /// while migrating clients to TLS, and takes precedence over `encrypt_server`.
+    ///
+    /// Note: This setting is only supported on unix platforms.
+    /// Default: false
+    pub detect_tls: bool,
 }
 
 impl Default for Settings {
@@ -464,7 +503,9 @@ impl Default for Settings {
             masking_strict: false,
             key_strict: false,
             method_strict: false,
+            protocol_strict: false,
             encrypt_server: false,
+            detect_tls: false,
         }
     }
 }
@@ -488,7 +529,7 @@ impl<F> WebSocket<F>
         config.notify_capacity(settings.max_connections * 5);  // every handler can do 5 things at once
         Ok(WebSocket {
             event_loop: try!(io::Loop::configured(config)),
-            handler: io::Handler::new(factory, settings),
+            handler: io::Handler::new(factory, settings, Arc::new(OriginPolicy::Any), Arc::new(Vec::new())),
         })
     }
 
@@ -498,7 +539,7 @@ impl<F> WebSocket<F>
         warn!("The with_config method is deprecated and will be removed in a future version.");
         Ok(WebSocket {
             event_loop: try!(io::Loop::configured(config)),
-            handler: io::Handler::new(factory, Settings::default()),
+            handler: io::Handler::new(factory, Settings::default(), Arc::new(OriginPolicy::Any), Arc::new(Vec::new())),
         })
     }
 
@@ -507,7 +548,16 @@ impl<F> WebSocket<F>
     /// # Safety
     ///
     /// This method will block until the event loop finishes running.
-    pub fn listen<A>(mut self, addr_spec: A) -> Result<WebSocket<F>>
+    pub fn listen<A>(self, addr_spec: A) -> Result<WebSocket<F>>
+        where A: ToSocketAddrs + fmt::Debug
+    {
+        try!(self.bind(addr_spec)).run()
+    }
+
+    /// Bind the WebSocket to the specified address without running it. New connections will be
+    /// accepted once `run` is called. Bind to port 0 to let the operating system pick a free
+    /// port, and use `local_addr` to find out which one it picked.
+    pub fn bind<A>(mut self, addr_spec: A) -> Result<WebSocket<F>>
         where A: ToSocketAddrs + fmt::Debug
     {
         let mut result = Err(Error::new(ErrorKind::Internal, format!("Unable to listen on {:?}", addr_spec)));
@@ -516,19 +566,30 @@ impl<F> WebSocket<F>
             result = self.handler.listen(&mut self.event_loop, &addr).map(|_| ());
             if result.is_ok() {
                 info!("Listening for new connections on {}.", addr);
-                return self.run()
+                return Ok(self)
             }
         }
 
         result.map(|_| self)
     }
 
+    /// Get the address that the WebSocket is listening on, once it has been bound.
+    pub fn local_addr(&self) -> ::std::io::Result<SocketAddr> {
+        self.handler.local_addr()
+    }
+
     /// Queue an outgoing connection on this WebSocket. This method may be called multiple times,
     /// but the actuall connections will not be established until after `run` is called.
     pub fn connect(&mut self, url: url::Url) -> Result<&mut WebSocket<F>> {
+        self.connect_with(url, ConnectOptions::new())
+    }
+
+    /// Queue an outgoing connection on this WebSocket, using the options to add headers such as
+    /// credentials to the handshake request.
+    pub fn connect_with(&mut self, url: url::Url, options: ConnectOptions) -> Result<&mut WebSocket<F>> {
         let sender = Sender::new(io::ALL, self.event_loop.channel());
         info!("Queuing connection to {}", url);
-        try!(sender.connect(url));
+        try!(sender.connect_with(url, options));
         Ok(self)
     }
 
@@ -555,6 +616,12 @@ impl<F> WebSocket<F>
 pub struct Builder {
     event_config: Option<EventLoopConfig>,
     settings: Settings,
+    origins: Arc<OriginPolicy>,
+    protocols: Arc<Vec<String>>,
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    tls: Option<TlsServerConfig>,
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    tls_client: Option<TlsClientConfig>,
 }
 
 // TODO: add convenience methods for each setting
@@ -564,6 +631,12 @@ impl Builder {
         Builder {
             event_config: None,
             settings: Settings::default(),
+            origins: Arc::new(OriginPolicy::Any),
+            protocols: Arc::new(Vec::new()),
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            tls: None,
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+            tls_client: None,
         }
     }
 
@@ -580,12 +653,36 @@ impl Builder {
             event_config = EventLoopConfig::default();
             event_config.notify_capacity(self.settings.max_connections * 5);
         }
+        let handler = io::Handler::new(factory, self.settings, self.origins.clone(), self.protocols.clone());
         Ok(WebSocket {
             event_loop: try!(io::Loop::configured(event_config)),
-            handler: io::Handler::new(factory, self.settings),
+            handler: self.configure_tls(handler),
         })
     }
 
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    fn configure_tls<F>(&self, handler: io::Handler<F>) -> io::Handler<F>
+        where F: Factory
+    {
+        let handler = if let Some(ref tls) = self.tls {
+            handler.with_tls(tls.clone())
+        } else {
+            handler
+        };
+        if let Some(ref config) = self.tls_client {
+            handler.with_tls_client(config.clone())
+        } else {
+            handler
+        }
+    }
+
+    #[cfg(not(any(all(not(windows), feature="ssl"), feature="rustls")))]
+    fn configure_tls<F>(&self, handler: io::Handler<F>) -> io::Handler<F>
+        where F: Factory
+    {
+        handler
+    }
+
     /// Set the EventLoopConfig to use with this WebSocket. If this is not set
     /// the builder will use a default EventLoopConfig based on other settings.
     pub fn with_config(&mut self, config: EventLoopConfig) -> &mut Builder {
@@ -598,4 +695,42 @@ impl Builder {
         self.settings = settings;
         self
     }
+
+    /// Set the policy deciding which origins may open connections to servers built with this
+    /// builder. Requests from disallowed origins are answered with HTTP 403 before
+    /// `Handler::on_request` is called.
+    pub fn with_origins(&mut self, policy: OriginPolicy) -> &mut Builder {
+        self.origins = Arc::new(policy);
+        self
+    }
+
+    /// Serve `wss` connections using the certificate and key in the TLS configuration. The
+    /// configuration is shared by every connection, so there is no need to override
+    /// `Handler::build_ssl`. This sets `Settings::encrypt_server`, and is required to serve
+    /// `wss` with the `rustls` backend.
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    pub fn with_tls(&mut self, tls: TlsServerConfig) -> &mut Builder {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Set how clients built with this builder verify the servers of `wss` connections. Without
+    /// this, servers are verified against the default roots and the host of the url, unless the
+    /// handler overrides `Handler::build_ssl` with the `ssl` feature.
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    pub fn with_tls_client(&mut self, config: TlsClientConfig) -> &mut Builder {
+        self.tls_client = Some(config);
+        self
+    }
+
+    /// Set the subprotocols supported by servers built with this builder, in order of
+    /// preference. During the handshake, the most preferred protocol that the client offers is
+    /// selected unless `Handler::on_request` has already set one on the response. See
+    /// `Settings::protocol_strict` for clients that offer none of them.
+    pub fn with_protocols<S>(&mut self, protocols: &[S]) -> &mut Builder
+        where S: AsRef<str>
+    {
+        self.protocols = Arc::new(protocols.iter().map(|proto| proto.as_ref().to_owned()).collect());
+        self
+    }
 }
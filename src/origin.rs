@@ -0,0 +1,124 @@
+use std::fmt;
+
+/// A policy deciding which browser origins may open WebSocket connections to a server.
+///
+/// Browsers allow any page to open a WebSocket to any server, sending the page's origin in the
+/// `Origin` header of the handshake request. Checking the origin protects servers that rely on
+/// cookies or other ambient credentials from cross-site WebSocket hijacking. The policy is
+/// enforced before `Handler::on_request` is called, and disallowed requests receive an HTTP 403
+/// response.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ws::{Builder, OriginPolicy};
+///
+/// let ws = Builder::new()
+///     .with_origins(OriginPolicy::List(vec![
+///         "https://example.com".into(),
+///         "https://*.example.com".into(),
+///     ]))
+///     .build(|out: ws::Sender| move |msg: ws::Message| out.send(msg))
+///     .unwrap();
+/// ```
+pub enum OriginPolicy {
+    /// Accept requests from any origin. This is the default.
+    Any,
+    /// Accept requests from the listed origins, such as `https://example.com`. An entry may use a
+    /// wildcard in place of the leftmost labels of the host to allow all subdomains, for example
+    /// `https://*.example.com`, which does not match `https://example.com` itself. Comparisons
+    /// ignore case. Requests without an `Origin` header do not come from browsers and are
+    /// accepted.
+    List(Vec<String>),
+    /// Decide using a function of the `Origin` header, which is `None` when the header is missing.
+    Custom(Box<Fn(Option<&str>) -> bool + Send + Sync>),
+}
+
+impl OriginPolicy {
+
+    /// Check whether a request with the given `Origin` header is allowed.
+    pub fn allows(&self, origin: Option<&str>) -> bool {
+        match *self {
+            OriginPolicy::Any => true,
+            OriginPolicy::List(ref allowed) => {
+                if let Some(origin) = origin {
+                    allowed.iter().any(|pattern| matches(pattern, origin))
+                } else {
+                    true
+                }
+            }
+            OriginPolicy::Custom(ref func) => func(origin),
+        }
+    }
+}
+
+impl Default for OriginPolicy {
+
+    fn default() -> OriginPolicy {
+        OriginPolicy::Any
+    }
+}
+
+impl fmt::Debug for OriginPolicy {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OriginPolicy::Any => write!(f, "Any"),
+            OriginPolicy::List(ref allowed) => write!(f, "List({:?})", allowed),
+            OriginPolicy::Custom(_) => write!(f, "Custom(<function>)"),
+        }
+    }
+}
+
+// Match an origin against an exact origin or one with a wildcard subdomain.
+fn matches(pattern: &str, origin: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let origin = origin.trim().to_lowercase();
+
+    if let Some(pos) = pattern.find("*.") {
+        let prefix = &pattern[..pos];
+        // keep the dot so that the wildcard matches at least one whole label
+        let suffix = &pattern[pos + 1..];
+
+        if origin.len() <= prefix.len() + suffix.len() {
+            return false
+        }
+        let labels = &origin[prefix.len()..origin.len() - suffix.len()];
+        origin.starts_with(prefix) &&
+            origin.ends_with(suffix) &&
+            !labels.contains('/') &&
+            !labels.contains(':')
+    } else {
+        pattern == origin
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn test_origin_list() {
+        let policy = OriginPolicy::List(vec![
+            "https://example.com".into(),
+            "https://*.example.org".into(),
+        ]);
+
+        assert!(policy.allows(None));
+        assert!(policy.allows(Some("https://example.com")));
+        assert!(policy.allows(Some("HTTPS://EXAMPLE.COM")));
+        assert!(policy.allows(Some("https://chat.example.org")));
+        assert!(policy.allows(Some("https://a.b.example.org")));
+        assert!(!policy.allows(Some("https://example.org")));
+        assert!(!policy.allows(Some("http://chat.example.org")));
+        assert!(!policy.allows(Some("https://example.com.evil.net")));
+        assert!(!policy.allows(Some("https://evilexample.org")));
+    }
+
+    #[test]
+    fn test_origin_custom() {
+        let policy = OriginPolicy::Custom(Box::new(|origin| origin == Some("null")));
+        assert!(policy.allows(Some("null")));
+        assert!(!policy.allows(None));
+    }
+}
@@ -0,0 +1,278 @@
+use std::io;
+use std::collections::VecDeque;
+
+use frame::Frame;
+use payload::Payload;
+use protocol::OpCode;
+use stream::Stream;
+
+// The most chunks passed to a single vectored write, well below the IOV_MAX of any platform.
+const MAX_CHUNKS: usize = 64;
+
+// A chunk of a frame, and whether it is the last chunk of the frame.
+type Chunk = (Payload, bool);
+
+/// A queue of the bytes waiting to be written to a connection.
+///
+/// Frames are queued as chunks for their headers and payloads, so payloads are never copied into
+/// the queue and written bytes never need to be moved out of the way.
+///
+/// Pings and pongs are kept in a queue of their own, which is written as soon as the frame that
+/// is currently being written is finished. A large message is sent as many fragments, so this
+/// keeps pings and pongs from waiting behind the whole message. Close frames stay in order
+/// because nothing may be sent after them.
+pub struct Output {
+    data: VecDeque<Chunk>,
+    control: VecDeque<Chunk>,
+    // the number of bytes at the start of the first chunk of each queue that have been written
+    data_offset: usize,
+    control_offset: usize,
+    // whether part of the first frame of the data queue has been written
+    mid_frame: bool,
+    len: usize,
+}
+
+impl Output {
+
+    pub fn new() -> Output {
+        Output {
+            data: VecDeque::new(),
+            control: VecDeque::new(),
+            data_offset: 0,
+            control_offset: 0,
+            mid_frame: false,
+            len: 0,
+        }
+    }
+
+    /// The number of bytes waiting to be written.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push_frame(&mut self, frame: Frame) {
+        let queue = match frame.opcode() {
+            OpCode::Ping | OpCode::Pong => &mut self.control,
+            _ => &mut self.data,
+        };
+        // the header is never empty, so there is always a last chunk
+        let chunks: Vec<Payload> = frame.into_chunks().into_iter().filter(|chunk| !chunk.is_empty()).collect();
+        let last = chunks.len();
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            self.len += chunk.len();
+            queue.push_back((chunk, i + 1 == last));
+        }
+    }
+
+    /// Write as much of the queue as the stream will take.
+    pub fn write_to(&mut self, stream: &mut Stream) -> io::Result<Option<usize>> {
+        let res = {
+            let mut bufs = Vec::with_capacity(MAX_CHUNKS);
+            let mut data = self.data.iter().enumerate();
+
+            // finish the current frame, then write the control frames before the next one
+            if self.mid_frame {
+                for (i, &(ref chunk, last)) in data.by_ref() {
+                    bufs.push(if i == 0 { &chunk[self.data_offset..] } else { &chunk[..] });
+                    if last {
+                        break
+                    }
+                }
+            }
+            for (i, &(ref chunk, _)) in self.control.iter().enumerate() {
+                bufs.push(if i == 0 { &chunk[self.control_offset..] } else { &chunk[..] });
+            }
+            for (i, &(ref chunk, _)) in data {
+                if bufs.len() >= MAX_CHUNKS {
+                    break
+                }
+                bufs.push(if i == 0 { &chunk[self.data_offset..] } else { &chunk[..] });
+            }
+
+            bufs.truncate(MAX_CHUNKS);
+            try!(stream.try_write_bufs(&bufs))
+        };
+        if let Some(len) = res {
+            self.consume(len);
+        }
+        Ok(res)
+    }
+
+    // Remove written bytes in the same order that `write_to` writes them.
+    fn consume(&mut self, mut len: usize) {
+        debug_assert!(len <= self.len, "Wrote more bytes than were queued!");
+        self.len -= len;
+        while len > 0 {
+            let from_data = self.mid_frame || self.control.is_empty();
+            let (queue, offset) = if from_data {
+                (&mut self.data, &mut self.data_offset)
+            } else {
+                (&mut self.control, &mut self.control_offset)
+            };
+
+            let remaining = queue[0].0.len() - *offset;
+            if len < remaining {
+                *offset += len;
+                if from_data {
+                    self.mid_frame = true;
+                }
+                return
+            }
+            len -= remaining;
+            *offset = 0;
+            let (_, last) = queue.pop_front().unwrap();
+            if from_data {
+                self.mid_frame = !last;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use mio::TryRead;
+    use stream::test::pair;
+
+    fn encode(frame: Frame) -> Vec<u8> {
+        let mut buf = Vec::new();
+        frame.clone().format(&mut buf).unwrap();
+        buf
+    }
+
+    // Read whatever has arrived on the stream.
+    fn read_available(stream: &mut Stream, bytes: &mut Vec<u8>) {
+        let mut buf = [0; 65536];
+        while let Some(len) = stream.try_read(&mut buf).unwrap() {
+            if len == 0 {
+                break
+            }
+            bytes.extend(&buf[..len]);
+        }
+    }
+
+    // Write the whole queue while reading from the other end, and return the bytes that arrived.
+    fn flush(out: &mut Output, writer: &mut Stream, reader: &mut Stream) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while !out.is_empty() {
+            out.write_to(writer).unwrap();
+            read_available(reader, &mut bytes);
+        }
+        bytes
+    }
+
+    // Read from the stream until it has produced the given number of bytes.
+    fn read_len(stream: &mut Stream, bytes: &mut Vec<u8>, len: usize) {
+        while bytes.len() < len {
+            read_available(stream, bytes);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_consume() {
+        let mut out = Output::new();
+        let first = Frame::message(vec![1, 2], OpCode::Binary, false);
+        let second = Frame::message(Vec::new(), OpCode::Continue, true);
+        let len = encode(first.clone()).len() + encode(second.clone()).len();
+        out.push_frame(first);
+        out.push_frame(second);
+        assert_eq!(out.len(), len);
+        assert_eq!(out.data.len(), 3);
+
+        out.consume(1);
+        assert_eq!(out.len(), len - 1);
+        assert_eq!(out.data_offset, 1);
+        assert!(out.mid_frame);
+
+        out.consume(3);
+        assert_eq!(out.data.len(), 1);
+        assert!(!out.mid_frame);
+
+        out.consume(2);
+        assert!(out.is_empty());
+        assert!(out.data.is_empty());
+        assert_eq!(out.data_offset, 0);
+    }
+
+    #[test]
+    fn test_write_to() {
+        let (mut writer, mut reader) = pair();
+        let mut out = Output::new();
+        let mut expected = Vec::new();
+        for i in 0..(MAX_CHUNKS + 10) {
+            let frame = Frame::message(vec![i as u8; i + 1], OpCode::Binary, true);
+            expected.extend(encode(frame.clone()));
+            out.push_frame(frame);
+        }
+
+        let mut bytes = flush(&mut out, &mut writer, &mut reader);
+        read_len(&mut reader, &mut bytes, expected.len());
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_control_between_fragments() {
+        let (mut writer, mut reader) = pair();
+        let mut out = Output::new();
+        // the first fragment is too large to be written at once
+        let first = Frame::message(vec![1; 1 << 24], OpCode::Text, false);
+        let second = Frame::message(vec![2; 10], OpCode::Continue, false);
+        let third = Frame::message(vec![3; 10], OpCode::Continue, true);
+        let ping = Frame::ping(vec![4]);
+        out.push_frame(first.clone());
+        out.push_frame(second.clone());
+        out.push_frame(third.clone());
+
+        let written = out.write_to(&mut writer).unwrap().unwrap();
+        assert!(written < encode(first.clone()).len());
+        out.push_frame(ping.clone());
+
+        let mut expected = encode(first);
+        expected.extend(encode(ping));
+        expected.extend(encode(second));
+        expected.extend(encode(third));
+
+        let mut bytes = flush(&mut out, &mut writer, &mut reader);
+        read_len(&mut reader, &mut bytes, expected.len() - written);
+        assert_eq!(bytes, &expected[written..]);
+    }
+
+    #[test]
+    fn test_close_after_data() {
+        let (mut writer, mut reader) = pair();
+        let mut out = Output::new();
+        let first = Frame::message(vec![1; 1 << 24], OpCode::Binary, false);
+        let second = Frame::message(vec![2; 10], OpCode::Continue, true);
+        let third = Frame::message(vec![3; 10], OpCode::Text, true);
+        let ping = Frame::ping(vec![4]);
+        let close = Frame::close(::protocol::CloseCode::Away, "");
+        out.push_frame(first.clone());
+        out.push_frame(second.clone());
+        out.push_frame(third.clone());
+
+        let written = out.write_to(&mut writer).unwrap().unwrap();
+        out.push_frame(close.clone());
+        out.push_frame(ping.clone());
+
+        // only the ping goes ahead, and every queued message is sent before the close
+        let mut expected = encode(first);
+        expected.extend(encode(ping));
+        expected.extend(encode(second));
+        expected.extend(encode(third));
+        expected.extend(encode(close));
+
+        let mut bytes = flush(&mut out, &mut writer, &mut reader);
+        read_len(&mut reader, &mut bytes, expected.len() - written);
+        assert_eq!(bytes, &expected[written..]);
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
+
+/// The data carried by a frame.
+///
+/// A Payload is reference counted, so cloning it or taking a slice of it does not copy the data.
+/// This allows a broadcast message to share one buffer between every connection, and a large
+/// message to be fragmented without copying each fragment.
+pub struct Payload {
+    data: Arc<Vec<u8>>,
+    // None when the payload covers the whole buffer
+    range: Option<(usize, usize)>,
+    // a copy of the range, made the first time that it is needed as a Vec
+    copy: OnceLock<Vec<u8>>,
+}
+
+impl Payload {
+
+    /// Create a payload that takes ownership of the data.
+    #[inline]
+    pub fn new(data: Vec<u8>) -> Payload {
+        Payload {
+            data: Arc::new(data),
+            range: None,
+            copy: OnceLock::new(),
+        }
+    }
+
+    /// Get a payload for part of this payload. The data is shared rather than copied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn slice(&self, start: usize, end: usize) -> Payload {
+        assert!(start <= end && end <= self.len(), "Payload slice is out of bounds.");
+        let offset = self.range.map(|(start, _)| start).unwrap_or(0);
+        let range = if offset + start == 0 && offset + end == self.data.len() {
+            None
+        } else {
+            Some((offset + start, offset + end))
+        };
+        Payload {
+            data: self.data.clone(),
+            range: range,
+            copy: OnceLock::new(),
+        }
+    }
+
+    /// Get the data as a Vec. A payload that is a slice of a larger buffer is copied the first
+    /// time this is called, so prefer dereferencing the payload to a slice.
+    pub fn as_vec(&self) -> &Vec<u8> {
+        match self.range {
+            Some(_) => self.copy.get_or_init(|| self.to_vec()),
+            None => &self.data,
+        }
+    }
+
+    /// Get a mutable reference to the data without copying it, unless it is shared with other
+    /// payloads or this payload is a slice.
+    pub fn get_mut(&mut self) -> Option<&mut Vec<u8>> {
+        if self.range.is_some() {
+            return None
+        }
+        Arc::get_mut(&mut self.data)
+    }
+
+    /// Get a mutable reference to the data. The data is copied first if it is shared with other
+    /// payloads.
+    pub fn to_mut(&mut self) -> &mut Vec<u8> {
+        if self.range.is_some() || Arc::get_mut(&mut self.data).is_none() {
+            *self = Payload::new(self.to_vec());
+        }
+        Arc::get_mut(&mut self.data).expect("Payload was not unique after copying.")
+    }
+
+    /// Consume the payload into a Vec. The data is only copied if it is shared with other payloads.
+    pub fn into_vec(self) -> Vec<u8> {
+        if let Some((start, end)) = self.range {
+            return match self.copy.into_inner() {
+                Some(copy) => copy,
+                None => self.data[start..end].to_vec(),
+            }
+        }
+        match Arc::try_unwrap(self.data) {
+            Ok(data) => data,
+            Err(data) => (*data).clone(),
+        }
+    }
+}
+
+// Clones share the data but not the copy of a slice.
+impl Clone for Payload {
+
+    fn clone(&self) -> Payload {
+        Payload {
+            data: self.data.clone(),
+            range: self.range,
+            copy: OnceLock::new(),
+        }
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match self.range {
+            Some((start, end)) => &self.data[start..end],
+            None => &self.data[..],
+        }
+    }
+}
+
+impl Default for Payload {
+
+    fn default() -> Payload {
+        Payload::new(Vec::new())
+    }
+}
+
+impl PartialEq for Payload {
+
+    fn eq(&self, other: &Payload) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for Payload {}
+
+impl fmt::Debug for Payload {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self[..], f)
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+
+    fn from(data: Vec<u8>) -> Payload {
+        Payload::new(data)
+    }
+}
+
+impl From<String> for Payload {
+
+    fn from(string: String) -> Payload {
+        Payload::new(string.into_bytes())
+    }
+}
+
+impl<'b> From<&'b [u8]> for Payload {
+
+    fn from(data: &'b [u8]) -> Payload {
+        Payload::new(data.to_vec())
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn test_slice_shares_data() {
+        let payload = Payload::new(vec![0, 1, 2, 3, 4, 5]);
+        let slice = payload.slice(1, 5);
+        assert_eq!(&slice[..], &[1, 2, 3, 4]);
+        assert_eq!(&slice.slice(1, 3)[..], &[2, 3]);
+        assert_eq!(slice.data.as_ptr(), payload.data.as_ptr());
+        assert_eq!(payload.clone().data.as_ptr(), payload.data.as_ptr());
+
+        // a slice of the whole buffer is the whole payload again
+        assert!(slice.slice(0, 4).range.is_some());
+        assert!(payload.slice(0, 6).range.is_none());
+    }
+
+    #[test]
+    fn test_as_vec() {
+        let payload = Payload::new(vec![0, 1, 2, 3]);
+        assert_eq!(payload.as_vec().as_ptr(), payload.data.as_ptr());
+
+        let slice = payload.slice(1, 3);
+        assert_eq!(slice.as_vec(), &vec![1, 2]);
+        // the copy is made once
+        assert_eq!(slice.as_vec().as_ptr(), slice.as_vec().as_ptr());
+        assert_eq!(slice.into_vec(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_to_mut_copies_shared() {
+        let payload = Payload::new(vec![1, 2, 3]);
+        let mut copy = payload.clone();
+        copy.to_mut().push(4);
+        assert_eq!(&payload[..], &[1, 2, 3]);
+        assert_eq!(&copy[..], &[1, 2, 3, 4]);
+
+        let mut slice = copy.slice(2, 4);
+        slice.to_mut()[0] = 0;
+        assert_eq!(&slice[..], &[0, 4]);
+        assert_eq!(&copy[..], &[1, 2, 3, 4]);
+
+        // a payload that is not shared is changed in place
+        let ptr = copy.as_vec().as_ptr();
+        copy.to_mut()[0] = 0;
+        assert_eq!(copy.as_vec().as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_into_vec() {
+        let data = vec![1, 2, 3];
+        let ptr = data.as_ptr();
+        assert_eq!(Payload::new(data).into_vec().as_ptr(), ptr);
+
+        let payload = Payload::new(vec![1, 2, 3]);
+        let other = payload.clone();
+        assert_eq!(payload.into_vec(), other.slice(0, 3).into_vec());
+    }
+}
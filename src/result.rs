@@ -8,8 +8,10 @@ use std::convert::{From, Into};
 
 use httparse;
 use mio;
-#[cfg(all(not(windows), feature="ssl"))]
+#[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
 use openssl::ssl::error::SslError;
+#[cfg(feature="rustls")]
+use rustls::Error as SslError;
 
 use communication::Command;
 
@@ -44,8 +46,9 @@ pub enum Kind {
     /// Indicates a failure to send a command on the internal EventLoop channel. This means that
     /// the WebSocket is overloaded and the Connection will disconnect.
     Queue(mio::NotifyError<Command>),
-    /// Indicates a failure to perform SSL encryption.
-    #[cfg(all(not(windows), feature="ssl"))]
+    /// Indicates a failure to perform SSL encryption. The error comes from whichever of the
+    /// `ssl` (openssl) or `rustls` backends is enabled, and from rustls if both are.
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
     Ssl(SslError),
     /// A custom error kind for use by applications. This error kind involves extra overhead
     /// because it will allocate the memory on the heap. The WebSocket ignores such errors by
@@ -109,7 +112,7 @@ impl StdError for Error {
             Kind::Encoding(ref err) => err.description(),
             Kind::Io(ref err)       => err.description(),
             Kind::Parse(_)          => "Unable to parse HTTP",
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
             Kind::Ssl(ref err)      => err.description(),
             Kind::Queue(_)          => "Unable to send signal on event loop",
             Kind::Custom(ref err)   => err.description(),
@@ -120,7 +123,7 @@ impl StdError for Error {
         match self.kind {
             Kind::Encoding(ref err) => Some(err),
             Kind::Io(ref err)       => Some(err),
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
             Kind::Ssl(ref err)      => Some(err),
             // Kind::Custom(ref err)   => Some(err.as_ref()),
             _ => None,
@@ -160,13 +163,22 @@ impl From<Utf8Error> for Error {
     }
 }
 
-#[cfg(all(not(windows), feature="ssl"))]
+#[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
 impl From<SslError> for Error {
     fn from(err: SslError) -> Error {
         Error::new(Kind::Ssl(err), "")
     }
 }
 
+// With both TLS features, openssl errors from handlers that build their own Ssl objects are
+// still converted, although the connections use rustls.
+#[cfg(all(not(windows), feature="ssl", feature="rustls"))]
+impl From<openssl::ssl::error::SslError> for Error {
+    fn from(err: openssl::ssl::error::SslError) -> Error {
+        Error::new(Kind::Custom(Box::new(err)), "")
+    }
+}
+
 impl<B> From<Box<B>> for Error
     where B: StdError + Send + Sync + 'static
 {
@@ -0,0 +1,353 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::convert::TryFrom;
+use std::result::Result as StdResult;
+
+use rustls;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::{DigitallySignedStruct, SignatureScheme, SupportedProtocolVersion};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::client::danger::{ServerCertVerifier, ServerCertVerified, HandshakeSignatureValid};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::pki_types::pem::{self, PemObject};
+use webpki_roots;
+use url::Url;
+
+use result::{Result, Error, Kind};
+use tls_config::{TlsClientConfig, TlsVersion, matches_host};
+
+static TLS13_ONLY: &'static [&'static SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+/// The per connection TLS state of this backend.
+pub type Session = rustls::Connection;
+
+fn pem_error(err: pem::Error) -> Error {
+    Error::new(Kind::Internal, format!("Unable to load PEM data: {:?}", err))
+}
+
+fn load_pem_files(cert_chain: &Path, key: &Path) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certs = try!(try!(CertificateDer::pem_file_iter(cert_chain).map_err(pem_error))
+                         .collect::<StdResult<Vec<_>, _>>()
+                         .map_err(pem_error));
+    let key = try!(PrivateKeyDer::from_pem_file(key).map_err(pem_error));
+    Ok((certs, key))
+}
+
+fn load_roots(ca_file: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in try!(CertificateDer::pem_file_iter(ca_file).map_err(pem_error)) {
+        try!(roots.add(try!(cert.map_err(pem_error))));
+    }
+    Ok(roots)
+}
+
+// Accepts any certificate, for clients that opted out of verification. Signatures are still
+// checked so that the handshake itself is sound.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+
+    fn verify_server_cert(&self, _: &CertificateDer, _: &[CertificateDer], _: &ServerName, _: &[u8], _: UnixTime)
+        -> StdResult<ServerCertVerified, rustls::Error>
+    {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer, dss: &DigitallySignedStruct)
+        -> StdResult<HandshakeSignatureValid, rustls::Error>
+    {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer, dss: &DigitallySignedStruct)
+        -> StdResult<HandshakeSignatureValid, rustls::Error>
+    {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// The configuration shared by the `wss` client connections of a WebSocket.
+pub struct ClientContext {
+    config: Arc<ClientConfig>,
+}
+
+impl ClientContext {
+
+    pub fn new(options: &TlsClientConfig) -> Result<ClientContext> {
+        let roots = match options.get_ca_file() {
+            Some(path) => try!(load_roots(path)),
+            None => {
+                let mut roots = RootCertStore::empty();
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                roots
+            }
+        };
+
+        // rustls does not implement anything older than TLS 1.2
+        let versions = if options.get_min_version() > TlsVersion::Tlsv1_2 {
+            TLS13_ONLY
+        } else {
+            rustls::ALL_VERSIONS
+        };
+
+        let builder = ClientConfig::builder_with_protocol_versions(versions)
+                          .with_root_certificates(roots);
+        let mut config = match options.get_certificate() {
+            Some((cert_chain, key)) => {
+                let (certs, key) = try!(load_pem_files(cert_chain, key));
+                try!(builder.with_client_auth_cert(certs, key))
+            }
+            None => builder.with_no_client_auth(),
+        };
+        if !options.verifies() {
+            let provider = config.crypto_provider().clone();
+            config.dangerous().set_certificate_verifier(Arc::new(NoVerification(provider)));
+        }
+        Ok(ClientContext {
+            config: Arc::new(config),
+        })
+    }
+}
+
+// The server name is taken from the url so that it is sent with SNI and the certificate is
+// verified against it.
+pub fn client_session(url: &Url, context: &ClientContext) -> Result<Session> {
+    let host = try!(url.serialize_host().ok_or(
+        Error::new(Kind::Internal, format!("Not a valid websocket url: {}", url))));
+    // ipv6 addresses are serialized within brackets
+    let name = try!(ServerName::try_from(host.trim_matches(|c| c == '[' || c == ']').to_owned())
+                        .map_err(|_| Error::new(Kind::Protocol, format!("Invalid server name: {}", host))));
+    let conn = try!(ClientConnection::new(context.config.clone(), name));
+    Ok(rustls::Connection::Client(conn))
+}
+
+pub fn server_session(config: &Option<TlsServerConfig>) -> Result<Option<Session>> {
+    match *config {
+        Some(ref config) => config.session().map(Some),
+        None => Err(Error::new(
+            Kind::Internal,
+            "Encrypting server connections with rustls requires Builder::with_tls.")),
+    }
+}
+
+// The certificate resolvers of the hosts added to a server configuration, by host name.
+type Hosts = Vec<(String, Arc<ResolvesServerCert>)>;
+
+// Pick the certificate of the host that the client asked for with SNI, falling back to the
+// default certificate.
+#[derive(Debug)]
+struct SelectHost {
+    default: Arc<ResolvesServerCert>,
+    hosts: Arc<Hosts>,
+}
+
+impl ResolvesServerCert for SelectHost {
+
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let resolver = hello.server_name().and_then(|name| {
+            self.hosts.iter().find(|&&(ref host, _)| matches_host(host, name))
+        }).map(|&(_, ref resolver)| resolver.clone());
+
+        match resolver {
+            Some(resolver) => resolver.resolve(hello),
+            None => self.default.resolve(hello),
+        }
+    }
+}
+
+/// The TLS configuration of a `wss` server.
+///
+/// The certificate chain and private key are loaded once and the resulting configuration is
+/// shared by all connections. Use `Builder::with_tls` to serve `wss` with this configuration.
+/// Cloning the configuration is cheap because it is reference counted.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ws::{Builder, TlsServerConfig};
+///
+/// let tls = TlsServerConfig::from_pem_files("cert.pem", "key.pem").unwrap();
+/// let ws = Builder::new()
+///     .with_tls(tls)
+///     .build(|out: ws::Sender| move |msg: ws::Message| out.send(msg))
+///     .unwrap();
+/// ```
+///
+/// Several domains can be served from the same listener by adding a certificate for each of them.
+/// The certificate is selected with the host name that the client sends with SNI, and the first
+/// certificate is used for clients that send no host name or one that was not added.
+///
+/// ```no_run
+/// use ws::TlsServerConfig;
+///
+/// let mut tls = TlsServerConfig::from_pem_files("default.pem", "default.key").unwrap();
+/// tls.add_host("chat.example.com", TlsServerConfig::from_pem_files("chat.pem", "chat.key").unwrap())
+///    .unwrap()
+///    .add_host("*.example.org", TlsServerConfig::from_pem_files("org.pem", "org.key").unwrap())
+///    .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct TlsServerConfig {
+    config: Arc<ServerConfig>,
+    default: Arc<ResolvesServerCert>,
+    hosts: Arc<Hosts>,
+}
+
+impl TlsServerConfig {
+
+    /// Load the certificate chain and private key from PEM files. The certificate file should
+    /// start with the server's certificate followed by any intermediate certificates.
+    pub fn from_pem_files<C, K>(cert_chain: C, key: K) -> Result<TlsServerConfig>
+        where C: AsRef<Path>, K: AsRef<Path>
+    {
+        let (certs, key) = try!(load_pem_files(cert_chain.as_ref(), key.as_ref()));
+        TlsServerConfig::from_der(certs, key)
+    }
+
+    /// Load the certificate chain and private key from PEM encoded bytes. The certificate chain
+    /// should start with the server's certificate followed by any intermediate certificates.
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<TlsServerConfig> {
+        let certs = try!(CertificateDer::pem_slice_iter(cert_chain)
+                             .collect::<StdResult<Vec<_>, _>>()
+                             .map_err(pem_error));
+        let key = try!(PrivateKeyDer::from_pem_slice(key).map_err(pem_error));
+        TlsServerConfig::from_der(certs, key)
+    }
+
+    fn from_der(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<TlsServerConfig> {
+        if certs.is_empty() {
+            return Err(Error::new(Kind::Internal, "Unable to find a PEM encoded certificate."))
+        }
+        let config = try!(ServerConfig::builder()
+                              .with_no_client_auth()
+                              .with_single_cert(certs, key));
+        Ok(TlsServerConfig::from_config(config))
+    }
+
+    /// Use a rustls server configuration that has already been built.
+    pub fn from_config(config: ServerConfig) -> TlsServerConfig {
+        TlsServerConfig {
+            default: config.cert_resolver.clone(),
+            config: Arc::new(config),
+            hosts: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Use the certificate of another configuration for clients that ask for this host with SNI.
+    /// The host may start with a wildcard label, such as `*.example.com`. Hosts must be added
+    /// before the configuration is cloned or passed to `Builder::with_tls`.
+    pub fn add_host<S>(&mut self, host: S, config: TlsServerConfig) -> Result<&mut TlsServerConfig>
+        where S: Into<String>
+    {
+        let mut hosts = (*self.hosts).clone();
+        hosts.push((host.into(), config.default));
+        let hosts = Arc::new(hosts);
+
+        {
+            let server = try!(Arc::get_mut(&mut self.config).ok_or(
+                Error::new(Kind::Internal, "Unable to add a host to a TLS configuration that has been shared.")));
+            server.cert_resolver = Arc::new(SelectHost {
+                default: self.default.clone(),
+                hosts: hosts.clone(),
+            });
+        }
+        // only list the host once the resolver can select it
+        self.hosts = hosts;
+        Ok(self)
+    }
+
+    /// Ask clients for a certificate signed by one of the CAs in a PEM encoded bundle. Clients
+    /// that present an invalid certificate are rejected during the TLS handshake, before
+    /// `Handler::on_request` is called, while clients without a certificate are accepted. The
+    /// certificate of a client is available from `Handshake::tls`.
+    ///
+    /// Note that this rebuilds the rustls configuration, so settings other than the certificates
+    /// of a configuration made with `from_config` are not kept.
+    pub fn request_client_certs<P>(&mut self, ca_file: P) -> Result<&mut TlsServerConfig>
+        where P: AsRef<Path>
+    {
+        self.verify_clients(ca_file.as_ref(), false)
+    }
+
+    /// Like `request_client_certs`, but clients without a certificate are rejected as well.
+    pub fn require_client_certs<P>(&mut self, ca_file: P) -> Result<&mut TlsServerConfig>
+        where P: AsRef<Path>
+    {
+        self.verify_clients(ca_file.as_ref(), true)
+    }
+
+    fn verify_clients(&mut self, ca_file: &Path, required: bool) -> Result<&mut TlsServerConfig> {
+        let builder = WebPkiClientVerifier::builder(Arc::new(try!(load_roots(ca_file))));
+        let builder = if required {
+            builder
+        } else {
+            builder.allow_unauthenticated()
+        };
+        let verifier = try!(builder.build().map_err(|err|
+            Error::new(Kind::Internal, format!("Unable to verify client certificates: {}", err))));
+
+        let config = ServerConfig::builder()
+                         .with_client_cert_verifier(verifier)
+                         .with_cert_resolver(self.config.cert_resolver.clone());
+        self.config = Arc::new(config);
+        Ok(self)
+    }
+
+    /// Create the rustls session for a new connection.
+    #[doc(hidden)]
+    pub fn session(&self) -> Result<Session> {
+        let conn = try!(ServerConnection::new(self.config.clone()));
+        Ok(rustls::Connection::Server(conn))
+    }
+}
+
+// Configurations are compared by identity, since the contexts cannot be compared.
+impl PartialEq for TlsServerConfig {
+
+    fn eq(&self, other: &TlsServerConfig) -> bool {
+        Arc::ptr_eq(&self.config, &other.config) && Arc::ptr_eq(&self.hosts, &other.hosts)
+    }
+}
+
+impl Eq for TlsServerConfig {}
+
+impl fmt::Debug for TlsServerConfig {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsServerConfig")
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use url::Url;
+
+    #[test]
+    fn test_client_session() {
+        let context = ClientContext::new(&TlsClientConfig::new()).unwrap();
+        let url = Url::parse("wss://example.com/chat").unwrap();
+        assert!(client_session(&url, &context).is_ok());
+
+        let url = Url::parse("wss://[::1]:3012").unwrap();
+        assert!(client_session(&url, &context).is_ok());
+
+        let mut config = TlsClientConfig::new();
+        config.danger_disable_verification().min_version(TlsVersion::Tlsv1_3);
+        assert!(ClientContext::new(&config).is_ok());
+    }
+
+    #[test]
+    fn test_missing_certificate() {
+        assert!(TlsServerConfig::from_pem(b"junk", b"junk").is_err());
+        assert!(server_session(&None).is_err());
+    }
+}
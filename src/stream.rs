@@ -1,25 +1,68 @@
 use std::io;
+#[cfg(feature="rustls")]
+use std::io::{Read, Write};
 use std::net::SocketAddr;
 
 use mio::{TryRead, TryWrite};
 use mio::tcp::TcpStream;
-#[cfg(all(not(windows), feature="ssl"))]
+#[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
 use openssl::ssl::NonblockingSslStream;
-#[cfg(all(not(windows), feature="ssl"))]
+#[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
 use openssl::ssl::error::NonblockingSslError;
+#[cfg(feature="rustls")]
+use rustls;
 
 use result::{Result, Error, Kind};
+use handshake::TlsInfo;
 
 use self::Stream::*;
 pub enum Stream {
     Tcp(TcpStream),
-    #[cfg(all(not(windows), feature="ssl"))]
+    #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
     Tls {
         sock: NonblockingSslStream<TcpStream>,
         negotiating: bool,
+    },
+    #[cfg(feature="rustls")]
+    Rustls {
+        sock: TcpStream,
+        session: Box<rustls::Connection>,
+        negotiating: bool,
+    },
+}
+
+// Write out as many buffered TLS records as the socket will take. Returns false if the socket
+// would block before all of them were written.
+#[cfg(feature="rustls")]
+fn flush_tls(session: &mut rustls::Connection, sock: &mut TcpStream) -> io::Result<bool> {
+    while session.wants_write() {
+        match session.write_tls(sock) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => return Err(err),
+        }
     }
+    Ok(true)
 }
 
+// Write several buffers with a single system call.
+#[cfg(unix)]
+fn writev(sock: &TcpStream, bufs: &[&[u8]]) -> io::Result<Option<usize>> {
+    use std::net;
+    use std::io::{IoSlice, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+
+    let slices: Vec<IoSlice> = bufs.iter().map(|buf| IoSlice::new(buf)).collect();
+    let mut sock = unsafe { net::TcpStream::from_raw_fd(sock.as_raw_fd()) };
+    let res = sock.write_vectored(&slices);
+    // the socket still belongs to mio
+    sock.into_raw_fd();
+    match res {
+        Ok(cnt) => Ok(Some(cnt)),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(err) => Err(err),
+    }
+}
 
 impl Stream {
 
@@ -27,33 +70,53 @@ impl Stream {
         Tcp(stream)
     }
 
-    #[cfg(all(not(windows), feature="ssl"))]
+    #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
     pub fn tls(stream: NonblockingSslStream<TcpStream>) -> Stream {
         Tls { sock: stream, negotiating: false }
     }
 
-    #[cfg(all(not(windows), feature="ssl"))]
+    #[cfg(feature="rustls")]
+    pub fn rustls(stream: TcpStream, session: rustls::Connection) -> Stream {
+        Rustls { sock: stream, session: Box::new(session), negotiating: false }
+    }
+
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
     pub fn is_tls(&self) -> bool {
         match *self {
             Tcp(_) => false,
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
             Tls {..} => true,
+            #[cfg(feature="rustls")]
+            Rustls {..} => true,
+        }
+    }
+
+    // Give up the socket of a plain connection so that it can be wrapped in TLS.
+    #[cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+    pub fn into_tcp(self) -> Option<TcpStream> {
+        match self {
+            Tcp(sock) => Some(sock),
+            _ => None,
         }
     }
 
     pub fn evented(&self) -> &TcpStream {
         match *self {
             Tcp(ref sock) => sock,
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
             Tls { ref sock, ..} => sock.get_ref(),
+            #[cfg(feature="rustls")]
+            Rustls { ref sock, ..} => sock,
         }
     }
 
     pub fn is_negotiating(&self) -> bool {
         match *self {
             Tcp(_) => false,
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
             Tls { sock: _, ref negotiating } => *negotiating,
+            #[cfg(feature="rustls")]
+            Rustls { ref negotiating, .. } => *negotiating,
         }
 
     }
@@ -62,24 +125,142 @@ impl Stream {
         debug!("Clearing negotiating status for {}", try!(self.peer_addr()));
         match *self {
             Tcp(_) => Err(Error::new(Kind::Internal, "Attempted to clear negotiating flag on non ssl connection.")),
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
             Tls { sock: _, ref mut negotiating } => Ok(*negotiating = false),
+            #[cfg(feature="rustls")]
+            Rustls { ref mut negotiating, .. } => Ok(*negotiating = false),
+        }
+    }
+
+    // Whether data that was accepted by `try_write` is still waiting to be written to the socket.
+    pub fn wants_write(&self) -> bool {
+        match *self {
+            #[cfg(feature="rustls")]
+            Rustls { ref session, .. } => session.wants_write(),
+            _ => false,
+        }
+    }
+
+    // The host name sent by the client with SNI.
+    pub fn server_name(&self) -> Option<String> {
+        match *self {
+            Tcp(_) => None,
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
+            Tls { ref sock, .. } => sock.ssl().get_servername(),
+            #[cfg(feature="rustls")]
+            Rustls { ref session, .. } => {
+                match **session {
+                    rustls::Connection::Server(ref conn) => conn.server_name().map(String::from),
+                    rustls::Connection::Client(_) => None,
+                }
+            }
+        }
+    }
+
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match *self {
+            Tcp(_) => None,
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
+            Tls { ref sock, .. } => {
+                let ssl = sock.ssl();
+                Some(TlsInfo {
+                    version: ssl.version().to_owned(),
+                    cipher: ssl.get_current_cipher().map(|cipher| cipher.name().to_owned()).unwrap_or_default(),
+                    // openssl only exposes ALPN behind its own alpn feature
+                    alpn_protocol: None,
+                    // openssl does not expose the rest of the chain
+                    peer_certificates: ssl.peer_certificate()
+                                          .and_then(|cert| cert.save_der().ok())
+                                          .into_iter()
+                                          .collect(),
+                })
+            }
+            #[cfg(feature="rustls")]
+            Rustls { ref session, .. } => {
+                let version = match session.protocol_version() {
+                    Some(rustls::ProtocolVersion::TLSv1_2) => "TLSv1.2".to_owned(),
+                    Some(rustls::ProtocolVersion::TLSv1_3) => "TLSv1.3".to_owned(),
+                    Some(version) => format!("{:?}", version),
+                    None => String::new(),
+                };
+                Some(TlsInfo {
+                    version: version,
+                    cipher: session.negotiated_cipher_suite()
+                                   .map(|suite| format!("{:?}", suite.suite()))
+                                   .unwrap_or_default(),
+                    alpn_protocol: session.alpn_protocol()
+                                          .map(|proto| String::from_utf8_lossy(proto).into_owned()),
+                    peer_certificates: session.peer_certificates()
+                                              .map(|certs| certs.iter().map(|cert| cert.as_ref().to_vec()).collect())
+                                              .unwrap_or_default(),
+                })
+            }
+        }
+    }
+
+    /// Write as many of the buffers as possible, in order. Plain TCP streams on unix write them
+    /// with a single vectored write, and rustls encrypts them all before writing the records
+    /// together. Other streams write them one at a time, since openssl must be given the same
+    /// buffer again when a write would block.
+    pub fn try_write_bufs(&mut self, bufs: &[&[u8]]) -> io::Result<Option<usize>> {
+        match *self {
+            #[cfg(unix)]
+            Tcp(ref sock) => writev(sock, bufs),
+            #[cfg(feature="rustls")]
+            Rustls { ref mut sock, ref mut session, ref mut negotiating } => {
+
+                *negotiating = false;
+
+                if !try!(flush_tls(session, sock)) {
+                    return Ok(None)
+                }
+                let mut total = 0;
+                for buf in bufs {
+                    let cnt = try!(session.writer().write(buf));
+                    total += cnt;
+                    if cnt < buf.len() {
+                        break
+                    }
+                }
+                try!(flush_tls(session, sock));
+                Ok(Some(total))
+            }
+            _ => {
+                let mut total = 0;
+                for buf in bufs {
+                    match try!(self.try_write(buf)) {
+                        Some(cnt) => {
+                            total += cnt;
+                            if cnt < buf.len() {
+                                break
+                            }
+                        }
+                        None if total == 0 => return Ok(None),
+                        None => break,
+                    }
+                }
+                Ok(Some(total))
+            }
         }
     }
 
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
         match *self {
             Tcp(ref sock) => sock.peer_addr(),
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
             Tls { ref sock, ..} => sock.get_ref().peer_addr(),
+            #[cfg(feature="rustls")]
+            Rustls { ref sock, ..} => sock.peer_addr(),
         }
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         match *self {
             Tcp(ref sock) => sock.local_addr(),
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
             Tls { ref sock, ..} => sock.get_ref().local_addr(),
+            #[cfg(feature="rustls")]
+            Rustls { ref sock, ..} => sock.local_addr(),
         }
     }
 }
@@ -89,7 +270,7 @@ impl TryRead for Stream {
     fn try_read(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
         match *self {
             Tcp(ref mut sock) => sock.try_read(buf),
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
             Tls { ref mut sock, ref mut negotiating } => {
                 match sock.read(buf) {
                     Ok(cnt) => Ok(Some(cnt)),
@@ -102,6 +283,43 @@ impl TryRead for Stream {
                     Err(NonblockingSslError::WantRead) => Ok(None),
                 }
             }
+            #[cfg(feature="rustls")]
+            Rustls { ref mut sock, ref mut session, ref mut negotiating } => {
+                let mut eof = false;
+                while session.wants_read() {
+                    match session.read_tls(sock) {
+                        Ok(0) => {
+                            eof = true;
+                            break
+                        }
+                        Ok(_) => {
+                            try!(session.process_new_packets().map_err(|err|
+                                io::Error::new(io::ErrorKind::InvalidData, err)));
+                        }
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+
+                // reading may have produced handshake records that need to go out
+                if !try!(flush_tls(session, sock)) {
+                    *negotiating = true;
+                }
+
+                match session.reader().read(buf) {
+                    Ok(cnt) => Ok(Some(cnt)),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        if eof {
+                            Ok(Some(0))
+                        } else {
+                            Ok(None)
+                        }
+                    }
+                    // the peer closed the socket without sending close_notify
+                    Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(Some(0)),
+                    Err(err) => Err(err),
+                }
+            }
         }
     }
 }
@@ -111,7 +329,7 @@ impl TryWrite for Stream {
     fn try_write(&mut self, buf: &[u8]) -> io::Result<Option<usize>> {
         match *self {
             Tcp(ref mut sock) => sock.try_write(buf),
-            #[cfg(all(not(windows), feature="ssl"))]
+            #[cfg(all(not(windows), feature="ssl", not(feature="rustls")))]
             Tls { ref mut sock, ref mut negotiating } => {
 
                 *negotiating = false;
@@ -127,6 +345,88 @@ impl TryWrite for Stream {
                     Err(NonblockingSslError::WantWrite) => Ok(None),
                 }
             }
+            #[cfg(feature="rustls")]
+            Rustls { ref mut sock, ref mut session, ref mut negotiating } => {
+
+                *negotiating = false;
+
+                // don't take on more data until the earlier records are on the wire
+                if !try!(flush_tls(session, sock)) {
+                    return Ok(None)
+                }
+                let cnt = try!(session.writer().write(buf));
+                // anything left over is written on the next writable event, see `wants_write`
+                try!(flush_tls(session, sock));
+                Ok(Some(cnt))
+            }
+        }
+    }
+}
+
+// Shared with the tests of the input and output buffers.
+#[cfg(test)]
+pub mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use mio::tcp::TcpListener;
+
+    /// Connect two plain streams to each other over loopback.
+    pub fn pair() -> (Stream, Stream) {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        loop {
+            if let Some((server, _)) = listener.accept().unwrap() {
+                return (Stream::tcp(client), Stream::tcp(server))
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Write all of the data, waiting whenever the stream would block.
+    pub fn write_all(stream: &mut Stream, mut data: &[u8]) {
+        while !data.is_empty() {
+            match stream.try_write(data).unwrap() {
+                Some(len) => data = &data[len..],
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_write_bufs() {
+        let (mut writer, mut reader) = pair();
+        // more than the socket buffers hold, so some writes stop in the middle of a buffer
+        let chunks: Vec<Vec<u8>> = (0..64).map(|i| vec![i as u8; 1 << 16]).collect();
+        let expected = chunks.concat();
+
+        let mut written = 0;
+        let mut partial = false;
+        let mut received = Vec::new();
+        let mut buf = vec![0; 1 << 16];
+        while received.len() < expected.len() {
+            if written < expected.len() {
+                let mut skip = written;
+                let mut bufs = Vec::new();
+                for chunk in chunks.iter() {
+                    if skip >= chunk.len() {
+                        skip -= chunk.len();
+                        continue
+                    }
+                    bufs.push(&chunk[skip..]);
+                    skip = 0;
+                }
+                if let Some(len) = writer.try_write_bufs(&bufs).unwrap() {
+                    written += len;
+                    partial = partial || (written < expected.len() && written % (1 << 16) != 0);
+                }
+            }
+            if let Some(len) = reader.try_read(&mut buf).unwrap() {
+                received.extend(&buf[..len]);
+            }
         }
+        assert!(partial);
+        assert_eq!(received, expected);
     }
 }
@@ -0,0 +1,201 @@
+//! A blocking client for scripts and tests.
+//!
+//! The `Client` runs the WebSocket protocol on an event loop in a background thread, so the
+//! calling thread is free to send and wait for messages one at a time.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use ws::sync::Client;
+//!
+//! let client = Client::connect("ws://127.0.0.1:3012").unwrap();
+//! client.send("Hello WebSocket").unwrap();
+//! println!("Got message: {}", client.recv().unwrap());
+//!
+//! match client.recv_timeout(Duration::from_secs(1)).unwrap() {
+//!     Some(msg) => println!("Got another message: {}", msg),
+//!     None => println!("No more messages."),
+//! }
+//! client.close(ws::CloseCode::Normal).unwrap();
+//! ```
+use std::borrow::Borrow;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use url;
+
+use communication::Sender;
+use handler::Handler;
+use handshake::Handshake;
+use message::Message;
+use protocol::CloseCode;
+use result::{Result, Error, Kind};
+use super::WebSocket;
+
+enum Event {
+    Open(Sender),
+    Message(Message),
+    Close(CloseCode, String),
+    Error(Error),
+}
+
+// Forwards the events of the connection to the thread that owns the Client.
+struct Bridge {
+    out: Sender,
+    events: mpsc::Sender<Event>,
+}
+
+impl Handler for Bridge {
+
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        let _ = self.events.send(Event::Open(self.out.clone()));
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let _ = self.events.send(Event::Message(msg));
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        let _ = self.events.send(Event::Close(code, reason.to_owned()));
+    }
+
+    fn on_error(&mut self, err: Error) {
+        let _ = self.events.send(Event::Error(err));
+        // the client has a single connection, so there is nothing left to do
+        let _ = self.out.shutdown();
+    }
+}
+
+fn closed(code: CloseCode, reason: &str) -> Error {
+    Error::new(Kind::Internal, format!("The connection was closed ({:?}) {}", code, reason))
+}
+
+fn disconnected() -> Error {
+    Error::new(Kind::Internal, "The connection is no longer running.")
+}
+
+/// A WebSocket client that blocks the calling thread until messages arrive.
+///
+/// The connection is shut down when the Client is dropped.
+pub struct Client {
+    out: Sender,
+    events: mpsc::Receiver<Event>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Client {
+
+    /// Connect to a WebSocket server, blocking until the handshake is complete.
+    pub fn connect<U>(url: U) -> Result<Client>
+        where U: Borrow<str>
+    {
+        let url = try!(
+            url::Url::parse(url.borrow())
+                .map_err(|err| Error::new(
+                    Kind::Internal,
+                    format!("Unable to parse {} as url due to {:?}", url.borrow(), err))));
+
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let errors = tx.clone();
+            let run = move || -> Result<()> {
+                let mut ws = try!(WebSocket::new(move |out: Sender| {
+                    Bridge {
+                        out: out,
+                        events: tx.clone(),
+                    }
+                }));
+                try!(ws.connect(url));
+                try!(ws.run());
+                Ok(())
+            };
+            if let Err(err) = run() {
+                let _ = errors.send(Event::Error(err));
+            }
+        });
+
+        loop {
+            match rx.recv() {
+                Ok(Event::Open(out)) => {
+                    return Ok(Client {
+                        out: out,
+                        events: rx,
+                        thread: Some(thread),
+                    })
+                }
+                Ok(Event::Error(err)) => return Err(err),
+                Ok(Event::Close(code, reason)) => return Err(closed(code, &reason)),
+                Ok(Event::Message(_)) => (),
+                Err(_) => return Err(disconnected()),
+            }
+        }
+    }
+
+    /// Send a message to the server.
+    pub fn send<M>(&self, msg: M) -> Result<()>
+        where M: Into<Message>
+    {
+        self.out.send(msg)
+    }
+
+    /// Send a ping to the server with the given test data. The pong is handled by the client.
+    pub fn ping(&self, data: Vec<u8>) -> Result<()> {
+        self.out.ping(data)
+    }
+
+    /// Block until the next message from the server arrives. This returns an error once the
+    /// connection has been closed.
+    pub fn recv(&self) -> Result<Message> {
+        match self.events.recv() {
+            Ok(event) => Client::into_message(event),
+            Err(_) => Err(disconnected()),
+        }
+    }
+
+    /// Block until the next message from the server arrives or the timeout elapses, in which
+    /// case None is returned.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Client::into_message(event).map(Some),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(disconnected()),
+        }
+    }
+
+    /// Close the connection and wait for the server to acknowledge it. Messages that arrive in
+    /// the meantime are discarded.
+    pub fn close(self, code: CloseCode) -> Result<()> {
+        try!(self.out.close(code));
+        loop {
+            match self.events.recv() {
+                Ok(Event::Error(err)) => return Err(err),
+                Ok(Event::Close(..)) | Err(_) => return Ok(()),
+                Ok(_) => (),
+            }
+        }
+    }
+
+    fn into_message(event: Event) -> Result<Message> {
+        match event {
+            Event::Message(msg) => Ok(msg),
+            Event::Close(code, reason) => Err(closed(code, &reason)),
+            Event::Error(err) => Err(err),
+            Event::Open(_) => Err(Error::new(Kind::Internal, "The connection was opened twice.")),
+        }
+    }
+}
+
+impl Drop for Client {
+
+    fn drop(&mut self) {
+        // the event loop is already gone if the connection was closed
+        let _ = self.out.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
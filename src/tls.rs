@@ -0,0 +1,410 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::io::Cursor;
+use std::sync::Arc;
+
+use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslContext, SslContextOptions, SslMethod, IntoSsl};
+use openssl::ssl::{SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3, SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1};
+use openssl::ssl::{SslVerifyMode, SSL_VERIFY_NONE, SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::ssl::{SSL_TLSEXT_ERR_OK, SSL_TLSEXT_ERR_NOACK};
+use openssl::x509::{X509, X509FileType, X509StoreContext};
+use openssl::crypto::pkey::PKey;
+
+use url::Url;
+
+use result::{Result, Error, Kind};
+use tls_config::{TlsClientConfig, TlsVersion, matches_host};
+
+/// The per connection TLS state of this backend.
+pub type Session = Ssl;
+
+static PEM_BEGIN: &'static str = "-----BEGIN ";
+static PEM_END: &'static str = "-----END ";
+
+// Split a buffer containing several PEM encoded objects into the individual objects.
+fn split_pem(data: &[u8]) -> Vec<&[u8]> {
+    let mut objects = Vec::new();
+    let mut rest = data;
+
+    while let Some(start) = find(rest, PEM_BEGIN.as_bytes()) {
+        let after = &rest[start..];
+        let end = match find(after, PEM_END.as_bytes()) {
+            Some(end) => end,
+            None => break,
+        };
+        // include the rest of the END line
+        let line_end = after[end..].iter()
+                                   .position(|&byte| byte == b'\n')
+                                   .map(|pos| end + pos + 1)
+                                   .unwrap_or(after.len());
+        objects.push(&after[..line_end]);
+        rest = &after[line_end..];
+    }
+    objects
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn server_context() -> Result<SslContext> {
+    SslContext::new(SslMethod::Sslv23).map_err(Error::from)
+}
+
+// Check the certificate of the server against the host once the chain itself has been verified.
+fn verify_host(host: &str, preverify_ok: bool, store: &X509StoreContext) -> bool {
+    if !preverify_ok {
+        return false
+    }
+    // only the certificate of the server itself names the host
+    if store.error_depth() != 0 {
+        return true
+    }
+    let cert = match store.get_current_cert() {
+        Some(cert) => cert,
+        None => return false,
+    };
+    let ip = host.parse::<IpAddr>().ok();
+
+    match cert.subject_alt_names() {
+        Some(names) => names.iter().any(|name| {
+            match ip {
+                Some(IpAddr::V4(ip)) => name.ipaddress().map_or(false, |addr| addr == &ip.octets()[..]),
+                Some(IpAddr::V6(ip)) => name.ipaddress().map_or(false, |addr| addr == &ip.octets()[..]),
+                None => name.dnsname().map_or(false, |name| matches_host(name, host)),
+            }
+        }),
+        // certificates without alternative names carry the host in the common name
+        None => ip.is_none() && cert.subject_name()
+                                    .text_by_nid(Nid::CN)
+                                    .map_or(false, |name| matches_host(&name, host)),
+    }
+}
+
+// The options disabling every protocol version older than the minimum. The openssl bindings
+// cannot negotiate TLS 1.3, so requiring it would leave no usable version.
+fn version_options(min: TlsVersion) -> Result<SslContextOptions> {
+    if min > TlsVersion::Tlsv1_2 {
+        return Err(Error::new(Kind::Internal, "The ssl feature does not support TLS 1.3, use the rustls feature to require it."))
+    }
+    let mut options = SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3;
+    if min > TlsVersion::Tlsv1 {
+        options = options | SSL_OP_NO_TLSV1;
+    }
+    if min > TlsVersion::Tlsv1_1 {
+        options = options | SSL_OP_NO_TLSV1_1;
+    }
+    Ok(options)
+}
+
+/// The context shared by the `wss` client connections of a WebSocket.
+pub struct ClientContext {
+    context: SslContext,
+    verify: bool,
+}
+
+impl ClientContext {
+
+    pub fn new(config: &TlsClientConfig) -> Result<ClientContext> {
+        let mut context = try!(SslContext::new(SslMethod::Sslv23));
+        context.set_options(try!(version_options(config.get_min_version())));
+        if let Some((cert_chain, key)) = config.get_certificate() {
+            try!(context.set_certificate_chain_file(cert_chain, X509FileType::PEM));
+            try!(context.set_private_key_file(key, X509FileType::PEM));
+            try!(context.check_private_key());
+        }
+        if config.verifies() {
+            match config.get_ca_file() {
+                Some(path) => try!(context.set_CA_file(path)),
+                None => try!(context.set_default_verify_paths()),
+            }
+            context.set_verify(SSL_VERIFY_PEER, None);
+        } else {
+            context.set_verify(SSL_VERIFY_NONE, None);
+        }
+        Ok(ClientContext {
+            context: context,
+            verify: config.verifies(),
+        })
+    }
+}
+
+// Build the Ssl object of a client connection, sending the host of the url with SNI and
+// checking that the certificate of the server is valid for it.
+pub fn client_session(url: &Url, context: &ClientContext) -> Result<Session> {
+    let host = try!(url.serialize_host().ok_or(
+        Error::new(Kind::Internal, format!("Not a valid websocket url: {}", url))));
+    // ipv6 addresses are serialized within brackets
+    let host = host.trim_matches(|c| c == '[' || c == ']').to_owned();
+
+    let mut ssl = try!(Ssl::new(&context.context));
+    if host.parse::<IpAddr>().is_err() {
+        try!(ssl.set_hostname(&host));
+    }
+    if context.verify {
+        ssl.set_verify_callback(SSL_VERIFY_PEER, move |ok, store| verify_host(&host, ok, store));
+    }
+    Ok(ssl)
+}
+
+// The Ssl object of a server that has neither a configuration nor a handler that builds one.
+pub fn default_server_ssl() -> Result<Session> {
+    let context = try!(SslContext::new(SslMethod::Tlsv1));
+    (&context).into_ssl().map_err(Error::from)
+}
+
+// Without a configuration the server falls back to `Handler::build_ssl`.
+pub fn server_session(config: &Option<TlsServerConfig>) -> Result<Option<Session>> {
+    match *config {
+        Some(ref config) => config.session().map(Some),
+        None => Ok(None),
+    }
+}
+
+// The contexts of the hosts added to a server configuration, by host name.
+type Hosts = Vec<(String, Arc<SslContext>)>;
+
+// Switch to the context of the host that the client asked for with SNI, keeping the default
+// context if there is no match.
+fn select_host(ssl: &mut Ssl, _: &mut i32, hosts: &Arc<Hosts>) -> i32 {
+    let name = match ssl.get_servername() {
+        Some(name) => name,
+        None => return SSL_TLSEXT_ERR_NOACK,
+    };
+    if let Some(&(_, ref context)) = hosts.iter().find(|&&(ref host, _)| matches_host(host, &name)) {
+        ssl.set_ssl_context(context);
+    }
+    SSL_TLSEXT_ERR_OK
+}
+
+/// The TLS configuration of a `wss` server.
+///
+/// The certificate chain and private key are loaded once and the resulting context is shared by
+/// all connections, so there is no need to override `Handler::build_ssl`. Use
+/// `Builder::with_tls` to serve `wss` with this configuration. Cloning the configuration is
+/// cheap because the context is reference counted.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ws::{Builder, TlsServerConfig};
+///
+/// let tls = TlsServerConfig::from_pem_files("cert.pem", "key.pem").unwrap();
+/// let ws = Builder::new()
+///     .with_tls(tls)
+///     .build(|out: ws::Sender| move |msg: ws::Message| out.send(msg))
+///     .unwrap();
+/// ```
+///
+/// Several domains can be served from the same listener by adding a certificate for each of them.
+/// The certificate is selected with the host name that the client sends with SNI, and the first
+/// certificate is used for clients that send no host name or one that was not added.
+///
+/// ```no_run
+/// use ws::TlsServerConfig;
+///
+/// let mut tls = TlsServerConfig::from_pem_files("default.pem", "default.key").unwrap();
+/// tls.add_host("chat.example.com", TlsServerConfig::from_pem_files("chat.pem", "chat.key").unwrap())
+///    .unwrap()
+///    .add_host("*.example.org", TlsServerConfig::from_pem_files("org.pem", "org.key").unwrap())
+///    .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct TlsServerConfig {
+    context: Arc<SslContext>,
+    hosts: Arc<Hosts>,
+    // the CA file and mode used to verify clients, which hosts added later must use as well
+    verify: Option<(PathBuf, SslVerifyMode)>,
+}
+
+impl TlsServerConfig {
+
+    /// Load the certificate chain and private key from PEM files. The certificate file should
+    /// start with the server's certificate followed by any intermediate certificates.
+    pub fn from_pem_files<C, K>(cert_chain: C, key: K) -> Result<TlsServerConfig>
+        where C: AsRef<Path>, K: AsRef<Path>
+    {
+        let mut context = try!(server_context());
+        try!(context.set_certificate_chain_file(cert_chain, X509FileType::PEM));
+        try!(context.set_private_key_file(key, X509FileType::PEM));
+        try!(context.check_private_key());
+        Ok(TlsServerConfig::from_context(context))
+    }
+
+    /// Load the certificate chain and private key from PEM encoded bytes. The certificate chain
+    /// should start with the server's certificate followed by any intermediate certificates.
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<TlsServerConfig> {
+        let mut context = try!(server_context());
+        let mut certs = split_pem(cert_chain).into_iter();
+
+        let cert = try!(certs.next().ok_or(
+            Error::new(Kind::Internal, "Unable to find a PEM encoded certificate.")));
+        try!(context.set_certificate(&try!(X509::from_pem(&mut Cursor::new(cert)))));
+        for cert in certs {
+            try!(context.add_extra_chain_cert(&try!(X509::from_pem(&mut Cursor::new(cert)))));
+        }
+
+        try!(context.set_private_key(&try!(PKey::private_key_from_pem(&mut Cursor::new(key)))));
+        try!(context.check_private_key());
+        Ok(TlsServerConfig::from_context(context))
+    }
+
+    /// Use a context that has already been configured.
+    pub fn from_context(context: SslContext) -> TlsServerConfig {
+        TlsServerConfig {
+            context: Arc::new(context),
+            hosts: Arc::new(Vec::new()),
+            verify: None,
+        }
+    }
+
+    /// Use the certificate of another configuration for clients that ask for this host with SNI.
+    /// The host may start with a wildcard label, such as `*.example.com`. Hosts must be added
+    /// before the configuration is cloned or passed to `Builder::with_tls`, and the configuration
+    /// of the host must not have been cloned, since it is changed to verify clients like this one.
+    pub fn add_host<S>(&mut self, host: S, mut config: TlsServerConfig) -> Result<&mut TlsServerConfig>
+        where S: Into<String>
+    {
+        // the host's context replaces this one during the handshake, so it verifies clients
+        if let Some((ca_file, mode)) = self.verify.clone() {
+            try!(config.verify_clients(&ca_file, mode));
+        }
+
+        let mut hosts = (*self.hosts).clone();
+        hosts.push((host.into(), config.context));
+        let hosts = Arc::new(hosts);
+
+        {
+            let context = try!(Arc::get_mut(&mut self.context).ok_or(
+                Error::new(Kind::Internal, "Unable to add a host to a TLS configuration that has been shared.")));
+            context.set_servername_callback_with_data(select_host, hosts.clone());
+        }
+        // only list the host once the callback can select it
+        self.hosts = hosts;
+        Ok(self)
+    }
+
+    /// Ask clients for a certificate signed by one of the CAs in a PEM encoded bundle. Clients
+    /// that present an invalid certificate are rejected during the TLS handshake, before
+    /// `Handler::on_request` is called, while clients without a certificate are accepted. The
+    /// certificate of a client is available from `Handshake::tls`. Clients must be verified
+    /// before hosts are added with `add_host`, which then verify clients in the same way.
+    pub fn request_client_certs<P>(&mut self, ca_file: P) -> Result<&mut TlsServerConfig>
+        where P: AsRef<Path>
+    {
+        self.verify_clients(ca_file.as_ref(), SSL_VERIFY_PEER)
+    }
+
+    /// Like `request_client_certs`, but clients without a certificate are rejected as well.
+    pub fn require_client_certs<P>(&mut self, ca_file: P) -> Result<&mut TlsServerConfig>
+        where P: AsRef<Path>
+    {
+        self.verify_clients(ca_file.as_ref(), SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT)
+    }
+
+    fn verify_clients(&mut self, ca_file: &Path, mode: SslVerifyMode) -> Result<&mut TlsServerConfig> {
+        if !self.hosts.is_empty() {
+            return Err(Error::new(Kind::Internal, "Clients must be verified before hosts are added to a TLS configuration."))
+        }
+        {
+            let context = try!(Arc::get_mut(&mut self.context).ok_or(
+                Error::new(Kind::Internal, "Unable to verify clients with a TLS configuration that has been shared.")));
+            try!(context.set_CA_file(ca_file));
+            context.set_verify(mode, None);
+        }
+        self.verify = Some((ca_file.to_path_buf(), mode));
+        Ok(self)
+    }
+
+    /// Create the Ssl object for a new connection.
+    #[doc(hidden)]
+    pub fn session(&self) -> Result<Session> {
+        Ssl::new(&self.context).map_err(Error::from)
+    }
+}
+
+// Configurations are compared by identity, since the contexts cannot be compared.
+impl PartialEq for TlsServerConfig {
+
+    fn eq(&self, other: &TlsServerConfig) -> bool {
+        Arc::ptr_eq(&self.context, &other.context) && Arc::ptr_eq(&self.hosts, &other.hosts)
+    }
+}
+
+impl Eq for TlsServerConfig {}
+
+impl fmt::Debug for TlsServerConfig {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsServerConfig")
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn test_split_pem() {
+        let pem = b"junk
+-----BEGIN CERTIFICATE-----
+AAAA
+-----END CERTIFICATE-----
+-----BEGIN CERTIFICATE-----
+BBBB
+-----END CERTIFICATE-----";
+        let objects = split_pem(pem);
+        assert_eq!(objects.len(), 2);
+        assert!(objects[0].starts_with(b"-----BEGIN CERTIFICATE-----\nAAAA"));
+        assert!(objects[0].ends_with(b"-----END CERTIFICATE-----\n"));
+        assert!(objects[1].ends_with(b"-----END CERTIFICATE-----"));
+    }
+
+    #[test]
+    fn test_min_version() {
+        assert!(version_options(TlsVersion::Tlsv1_2).is_ok());
+        assert!(version_options(TlsVersion::Tlsv1_3).is_err());
+        let mut config = TlsClientConfig::new();
+        config.min_version(TlsVersion::Tlsv1_3);
+        assert!(ClientContext::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_add_host_to_shared_config() {
+        let mut config = TlsServerConfig::from_context(server_context().unwrap());
+        let host = TlsServerConfig::from_context(server_context().unwrap());
+        let shared = config.clone();
+        assert!(config.add_host("example.com", host.clone()).is_err());
+        assert!(config.hosts.is_empty());
+        drop(shared);
+
+        assert!(config.add_host("example.com", host).is_ok());
+        assert_eq!(config.hosts.len(), 1);
+    }
+
+    #[test]
+    fn test_verify_clients_of_hosts() {
+        let ca_file = "tests/certs/ca.pem";
+        let mut config = TlsServerConfig::from_context(server_context().unwrap());
+        config.require_client_certs(ca_file).unwrap();
+
+        // the context of a host must be changed to verify clients too
+        let host = TlsServerConfig::from_context(server_context().unwrap());
+        let shared = host.clone();
+        assert!(config.add_host("example.com", host).is_err());
+        assert!(config.add_host("example.com", shared).is_ok());
+        assert_eq!(config.hosts.len(), 1);
+
+        assert!(config.request_client_certs(ca_file).is_err());
+    }
+
+    #[test]
+    fn test_config_identity() {
+        let config = TlsServerConfig::from_context(server_context().unwrap());
+        let other = TlsServerConfig::from_context(server_context().unwrap());
+        assert_eq!(config, config.clone());
+        assert!(config != other);
+    }
+}
@@ -0,0 +1,161 @@
+use std::path::{Path, PathBuf};
+
+/// A version of the TLS protocol.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum TlsVersion {
+    /// TLS 1.0
+    Tlsv1,
+    /// TLS 1.1
+    Tlsv1_1,
+    /// TLS 1.2
+    Tlsv1_2,
+    /// TLS 1.3
+    Tlsv1_3,
+}
+
+/// How clients verify the servers of `wss` connections.
+///
+/// By default, the certificate chain of the server is verified against the system's root
+/// certificates (or the Mozilla roots bundled with `webpki-roots` when using the `rustls`
+/// feature), the certificate must be valid for the host of the url, the host is sent with SNI
+/// and TLS 1.2 is the minimum protocol version. Use `Builder::with_tls_client` to apply a
+/// configuration to a WebSocket.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ws::{Builder, TlsClientConfig, TlsVersion};
+///
+/// let mut tls = TlsClientConfig::new();
+/// tls.ca_file("internal-ca.pem").min_version(TlsVersion::Tlsv1_3);
+///
+/// let mut ws = Builder::new()
+///     .with_tls_client(tls)
+///     .build(|out: ws::Sender| move |msg: ws::Message| out.close(ws::CloseCode::Normal))
+///     .unwrap();
+/// ws.connect("wss://internal.example.com".parse().unwrap()).unwrap();
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TlsClientConfig {
+    ca_file: Option<PathBuf>,
+    certificate: Option<(PathBuf, PathBuf)>,
+    min_version: TlsVersion,
+    verify: bool,
+}
+
+impl TlsClientConfig {
+
+    /// Create a configuration that verifies servers against the default roots.
+    pub fn new() -> TlsClientConfig {
+        TlsClientConfig::default()
+    }
+
+    /// Verify servers against the certificates in a PEM encoded CA bundle instead of the
+    /// default roots.
+    pub fn ca_file<P>(&mut self, path: P) -> &mut TlsClientConfig
+        where P: AsRef<Path>
+    {
+        self.ca_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Present the certificate chain and private key in these PEM files to servers that ask
+    /// for a client certificate. The certificate file should start with the client's certificate
+    /// followed by any intermediate certificates.
+    pub fn certificate<C, K>(&mut self, cert_chain: C, key: K) -> &mut TlsClientConfig
+        where C: AsRef<Path>, K: AsRef<Path>
+    {
+        self.certificate = Some((cert_chain.as_ref().to_path_buf(), key.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Refuse to negotiate TLS versions older than this one. Note that the `rustls` feature
+    /// does not support versions older than TLS 1.2, and the `ssl` feature cannot require TLS 1.3.
+    pub fn min_version(&mut self, version: TlsVersion) -> &mut TlsClientConfig {
+        self.min_version = version;
+        self
+    }
+
+    /// Accept any certificate for any host. This makes the connection trivial to intercept and
+    /// is only meant for testing against servers with self-signed certificates.
+    pub fn danger_disable_verification(&mut self) -> &mut TlsClientConfig {
+        self.verify = false;
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn get_ca_file(&self) -> Option<&Path> {
+        self.ca_file.as_ref().map(|path| path.as_path())
+    }
+
+    #[doc(hidden)]
+    pub fn get_certificate(&self) -> Option<(&Path, &Path)> {
+        self.certificate.as_ref().map(|&(ref cert_chain, ref key)| (cert_chain.as_path(), key.as_path()))
+    }
+
+    #[doc(hidden)]
+    pub fn get_min_version(&self) -> TlsVersion {
+        self.min_version
+    }
+
+    #[doc(hidden)]
+    pub fn verifies(&self) -> bool {
+        self.verify
+    }
+}
+
+impl Default for TlsClientConfig {
+
+    fn default() -> TlsClientConfig {
+        TlsClientConfig {
+            ca_file: None,
+            certificate: None,
+            min_version: TlsVersion::Tlsv1_2,
+            verify: true,
+        }
+    }
+}
+
+// Match a host name against a name from a certificate or configuration, which may start with a
+// wildcard label covering exactly one label of the host.
+pub fn matches_host(name: &str, host: &str) -> bool {
+    let name = name.trim_right_matches('.').to_lowercase();
+    let host = host.trim_right_matches('.').to_lowercase();
+
+    if name.starts_with("*.") {
+        match host.find('.') {
+            Some(dot) => dot > 0 && host[dot..] == name[1..],
+            None => false,
+        }
+    } else {
+        name == host
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_default_config() {
+        let mut config = TlsClientConfig::new();
+        assert!(config.verifies());
+        assert_eq!(config.get_min_version(), TlsVersion::Tlsv1_2);
+        config.danger_disable_verification();
+        assert!(!config.verifies());
+
+        assert!(config.get_certificate().is_none());
+        config.certificate("client.pem", "client.key");
+        assert_eq!(config.get_certificate(), Some((Path::new("client.pem"), Path::new("client.key"))));
+    }
+
+    #[test]
+    fn test_matches_host() {
+        assert!(matches_host("example.com", "EXAMPLE.com"));
+        assert!(matches_host("*.example.com", "chat.example.com"));
+        assert!(!matches_host("*.example.com", "example.com"));
+        assert!(!matches_host("*.example.com", "a.chat.example.com"));
+        assert!(!matches_host("example.com", "evil-example.com"));
+    }
+}
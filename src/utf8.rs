@@ -0,0 +1,107 @@
+use std::str::{from_utf8, Utf8Error};
+use std::result::Result as StdResult;
+
+/// Validates the UTF-8 of a text message one fragment at a time, so that invalid data is found as
+/// soon as it arrives rather than once the whole message has been received.
+///
+/// A code point may be split between fragments, so the bytes at the end of a fragment that start
+/// a code point are kept until the next fragment completes it.
+pub struct Validator {
+    partial: [u8; 4],
+    len: usize,
+}
+
+impl Validator {
+
+    pub fn new() -> Validator {
+        Validator {
+            partial: [0; 4],
+            len: 0,
+        }
+    }
+
+    /// Validate the next fragment of the message.
+    pub fn validate(&mut self, mut data: &[u8]) -> StdResult<(), Utf8Error> {
+        // finish the code point left over from the last fragment
+        while self.len > 0 && !data.is_empty() {
+            self.partial[self.len] = data[0];
+            self.len += 1;
+            data = &data[1..];
+            match from_utf8(&self.partial[..self.len]) {
+                Ok(_) => self.len = 0,
+                // a code point has at most four bytes, so those always end in an error or Ok
+                Err(err) => if err.error_len().is_some() {
+                    self.len = 0;
+                    return Err(err)
+                },
+            }
+        }
+
+        match from_utf8(data) {
+            Ok(_) => Ok(()),
+            Err(err) => match err.error_len() {
+                Some(_) => Err(err),
+                None => {
+                    // the fragment ends part of the way through a code point
+                    let rest = &data[err.valid_up_to()..];
+                    self.partial[..rest.len()].copy_from_slice(rest);
+                    self.len = rest.len();
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    /// Check that the message did not end part of the way through a code point.
+    pub fn finish(&mut self) -> StdResult<(), Utf8Error> {
+        if self.len > 0 {
+            let res = from_utf8(&self.partial[..self.len]).map(|_| ());
+            self.len = 0;
+            res
+        } else {
+            Ok(())
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    fn validate(fragments: &[&[u8]]) -> StdResult<(), Utf8Error> {
+        let mut validator = Validator::new();
+        for fragment in fragments {
+            try!(validator.validate(fragment));
+        }
+        validator.finish()
+    }
+
+    #[test]
+    fn test_split_code_points() {
+        let text = "κόσμε 𝄞 text".as_bytes();
+        // split the text at every possible point, including inside code points
+        for i in 0..text.len() {
+            for j in i..text.len() {
+                assert!(validate(&[&text[..i], &text[i..j], &text[j..]]).is_ok());
+            }
+        }
+        assert!(validate(&[&[0xf0], &[0x9d], &[], &[0x84], &[0x9e]]).is_ok());
+    }
+
+    #[test]
+    fn test_fail_fast() {
+        let mut validator = Validator::new();
+        assert!(validator.validate(b"hello").is_ok());
+        assert!(validator.validate(&[0xce, 0xba, 0xff]).is_err());
+
+        let mut validator = Validator::new();
+        assert!(validator.validate(&[0xe2, 0x82]).is_ok());
+        assert!(validator.validate(&[0x41]).is_err());
+    }
+
+    #[test]
+    fn test_incomplete_message() {
+        assert!(validate(&[b"abc", &[0xe2, 0x82]]).is_err());
+        assert!(validate(&[&[0xf0, 0x9d, 0x84]]).is_err());
+    }
+}
@@ -0,0 +1,15 @@
+extern crate ws;
+
+use std::net::TcpStream;
+
+#[test]
+fn bind_to_free_port() {
+    let server = ws::WebSocket::new(|_: ws::Sender| |_: ws::Message| Ok(())).unwrap();
+    assert!(server.local_addr().is_err());
+
+    let server = server.bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    assert!(addr.port() != 0);
+    // clients may connect before the server runs
+    assert!(TcpStream::connect(addr).is_ok());
+}
@@ -0,0 +1,26 @@
+#!/bin/sh
+# Generate the certificates used by the TLS tests. The keys are for testing only.
+cd "$(dirname "$0")"
+set -e
+days=36500
+openssl req -x509 -newkey rsa:2048 -nodes -keyout ca.key -out ca.pem -days $days -subj "/CN=ws-rs test CA" \
+  -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign" 2>/dev/null
+leaf() {
+  name=$1; cn=$2; ext=$3; ca=$4; cakey=$5
+  openssl req -newkey rsa:2048 -nodes -keyout $name.key -out $name.csr -subj "/CN=$cn" 2>/dev/null
+  printf "%s\n" "$ext" > $name.ext
+  openssl x509 -req -in $name.csr -CA $ca -CAkey $cakey -CAcreateserial -out $name.pem -days $days -extfile $name.ext 2>/dev/null
+  rm $name.csr $name.ext
+}
+leaf server localhost "basicConstraints=critical,CA:FALSE
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=serverAuth
+subjectAltName=DNS:localhost,IP:127.0.0.1" ca.pem ca.key
+leaf client client "basicConstraints=critical,CA:FALSE
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=clientAuth" ca.pem ca.key
+# a client certificate that no configured CA has signed
+openssl req -x509 -newkey rsa:2048 -nodes -keyout untrusted.key -out untrusted.pem -days $days -subj "/CN=untrusted" \
+  -addext "basicConstraints=critical,CA:FALSE" -addext "extendedKeyUsage=clientAuth" 2>/dev/null
+rm -f ca.srl
+rm ca.key
@@ -0,0 +1,36 @@
+// Servers shared by the integration tests. Not every test uses all of them.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+
+use ws::{Builder, Factory, Handler, Message, Result, Sender};
+
+/// A handler that sends every message back.
+pub struct Echo(pub Sender);
+
+impl Handler for Echo {
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.0.send(msg)
+    }
+}
+
+/// Run a server built with the builder on a free port, and return its broadcaster, its address
+/// and the thread that runs it.
+pub fn serve<F>(builder: &Builder, factory: F) -> (Sender, SocketAddr, JoinHandle<()>)
+    where F: Factory + Send + 'static, F::Handler: Send
+{
+    let server = builder.build(factory).unwrap().bind("127.0.0.1:0").unwrap();
+    let handle = server.broadcaster();
+    let addr = server.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    (handle, addr, t)
+}
+
+/// Run an echo server on a free port.
+pub fn echo_server() -> (Sender, SocketAddr, JoinHandle<()>) {
+    serve(&Builder::new(), Echo)
+}
@@ -0,0 +1,117 @@
+extern crate ws;
+
+mod common;
+
+use std::cell::Cell;
+use std::sync::mpsc;
+
+use ws::{CloseCode, Frame, Handler, Handshake, OpCode, Message, Payload, Result, Sender};
+
+use common::{echo_server, serve};
+
+#[test]
+fn send_fragmented_frames() {
+    let (handle, addr, t) = echo_server();
+
+    ws::connect(format!("ws://{}", addr), |out: ws::Sender| {
+        // a ping may be sent between the fragments of a message
+        out.send_frame(Frame::message(Vec::from("Hel"), OpCode::Text, false)).unwrap();
+        out.send_frame(Frame::ping(vec![1])).unwrap();
+        out.send_frame(Frame::message(Vec::from("lo"), OpCode::Continue, true)).unwrap();
+
+        assert!(out.send_frame(Frame::message(vec![0; 200], OpCode::Text, true).set_opcode(OpCode::Ping).clone()).is_err());
+
+        move |msg| {
+            assert_eq!(msg, Message::text("Hello"));
+            out.close(ws::CloseCode::Normal)
+        }
+    }).unwrap();
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn send_shared_messages() {
+    let (handle, addr, t) = echo_server();
+
+    ws::connect(format!("ws://{}", addr), |out: ws::Sender| {
+        let text = Payload::new(Vec::from("Hello"));
+        out.send_shared(text.clone(), OpCode::Text).unwrap();
+        out.send_shared(text, OpCode::Binary).unwrap();
+
+        assert!(out.send_shared(Payload::new(vec![0xff]), OpCode::Text).is_err());
+        assert!(out.send_shared(Payload::new(vec![1]), OpCode::Ping).is_err());
+
+        let received = Cell::new(0);
+        move |msg| {
+            received.set(received.get() + 1);
+            if received.get() == 1 {
+                assert_eq!(msg, Message::text("Hello"));
+                Ok(())
+            } else {
+                assert_eq!(msg, Message::binary("Hello"));
+                out.close(ws::CloseCode::Normal)
+            }
+        }
+    }).unwrap();
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+struct Invalid {
+    out: Sender,
+    closed: mpsc::Sender<CloseCode>,
+}
+
+impl Handler for Invalid {
+
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        // the final fragment is never sent, so the server must not wait for it
+        self.out.send_frame(Frame::message(vec![b'a', 0xff], OpCode::Text, false))
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+#[test]
+fn invalid_first_fragment() {
+    let (handle, addr, t) = echo_server();
+
+    let (tx, rx) = mpsc::channel();
+    ws::connect(format!("ws://{}", addr), |out: ws::Sender| {
+        Invalid { out: out, closed: tx.clone() }
+    }).unwrap();
+    assert_eq!(rx.recv().unwrap(), CloseCode::Invalid);
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn send_then_close() {
+    // the server closes right after replying, and the reply must still arrive
+    let (handle, addr, t) = serve(&ws::Builder::new(), |out: ws::Sender| {
+        move |msg| {
+            try!(out.send(msg));
+            out.close(CloseCode::Normal)
+        }
+    });
+
+    let (tx, rx) = mpsc::channel();
+    ws::connect(format!("ws://{}", addr), |out: ws::Sender| {
+        out.send("Hello").unwrap();
+        let tx = tx.clone();
+        move |msg| {
+            tx.send(msg).unwrap();
+            Ok(())
+        }
+    }).unwrap();
+    assert_eq!(rx.try_iter().collect::<Vec<Message>>(), vec![Message::text("Hello")]);
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
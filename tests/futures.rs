@@ -0,0 +1,101 @@
+#![cfg(feature="futures")]
+extern crate futures;
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, stream, Future, Sink, Stream};
+
+use ws::futures_adapter::{connect, listen};
+
+#[test]
+fn futures_echo() {
+    let incoming = listen("127.0.0.1:0").unwrap();
+    let handle = incoming.broadcaster().clone();
+    let addr = incoming.local_addr();
+
+    let t = thread::spawn(move || {
+        incoming.take(1).for_each(|conn| {
+            let (sink, stream) = conn.split();
+            stream.forward(sink).map(|_| ())
+        }).wait().unwrap();
+    });
+
+    let conn = connect(format!("ws://{}", addr)).unwrap().wait().unwrap();
+    let conn = conn.send(ws::Message::text("Hello")).wait().unwrap();
+    let (msg, conn) = conn.into_future().wait().map_err(|(err, _)| err).unwrap();
+    assert_eq!(msg, Some(ws::Message::text("Hello")));
+
+    let mut conn = conn;
+    future::poll_fn(|| conn.close()).wait().unwrap();
+    assert!(conn.wait().all(|msg| msg.is_ok()));
+
+    assert!(t.join().is_ok());
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn futures_bad_handshake() {
+    let incoming = listen("127.0.0.1:0").unwrap();
+    let handle = incoming.broadcaster().clone();
+    let addr = incoming.local_addr();
+
+    let t = thread::spawn(move || {
+        incoming.take(1).for_each(|conn| {
+            let (sink, stream) = conn.split();
+            stream.forward(sink).map(|_| ())
+        }).wait().unwrap();
+    });
+
+    // a client that does not speak WebSocket must not end the stream of connections
+    let mut garbage = TcpStream::connect(addr).unwrap();
+    garbage.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    garbage.write_all(b"not a handshake\r\n\r\n").unwrap();
+    let _ = garbage.read_to_end(&mut Vec::new());
+
+    let conn = connect(format!("ws://{}", addr)).unwrap().wait().unwrap();
+    let conn = conn.send(ws::Message::text("Hello")).wait().unwrap();
+    let (msg, mut conn) = conn.into_future().wait().map_err(|(err, _)| err).unwrap();
+    assert_eq!(msg, Some(ws::Message::text("Hello")));
+
+    future::poll_fn(|| conn.close()).wait().unwrap();
+    assert!(conn.wait().all(|msg| msg.is_ok()));
+
+    assert!(t.join().is_ok());
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn futures_many_messages() {
+    let incoming = listen("127.0.0.1:0").unwrap();
+    let handle = incoming.broadcaster().clone();
+    let addr = incoming.local_addr();
+
+    let t = thread::spawn(move || {
+        incoming.take(1).for_each(|conn| {
+            let (sink, stream) = conn.split();
+            stream.forward(sink).map(|_| ())
+        }).wait().unwrap();
+    });
+
+    // more messages than the sink lets wait for the socket
+    let messages: Vec<ws::Message> = (0..100u8).map(|i| ws::Message::binary(vec![i; 1 << 18])).collect();
+    let conn = connect(format!("ws://{}", addr)).unwrap().wait().unwrap();
+    let (sink, stream) = conn.split();
+    let sent = thread::spawn(move || {
+        let sink = sink.send_all(stream::iter_ok::<_, ws::Error>(messages)).wait().unwrap().0;
+        // the messages have all been written once sending completes
+        let mut sink = sink;
+        future::poll_fn(|| sink.close()).wait().unwrap();
+    });
+
+    let received: Vec<u8> = stream.take(100).map(|msg| msg.into_data()[0]).collect().wait().unwrap();
+    assert_eq!(received, (0..100).collect::<Vec<u8>>());
+
+    assert!(sent.join().is_ok());
+    assert!(t.join().is_ok());
+    handle.shutdown().unwrap();
+}
@@ -0,0 +1,54 @@
+extern crate ws;
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use ws::{Handler, Request, Response, Result, Sender};
+
+use common::serve;
+
+struct Server;
+
+impl Handler for Server {
+
+    fn on_http_request(&mut self, req: &Request) -> Result<Response> {
+        Ok(Response::new(200, "OK", req.resource().as_bytes().to_vec()))
+    }
+}
+
+// Send raw bytes to a new server and read everything it writes back until it disconnects.
+fn exchange(request: &[u8]) -> String {
+    let (handle, addr, t) = serve(&ws::Builder::new(), |_: Sender| Server);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(request).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+    response
+}
+
+#[test]
+fn http_pipelined_requests() {
+    let response = exchange(b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                              GET /second HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    let first = response.find("\r\n\r\n/first").unwrap();
+    let second = response.rfind("HTTP/1.1 200 OK\r\n").unwrap();
+    assert!(first < second);
+    assert!(response.ends_with("\r\n\r\n/second"));
+}
+
+#[test]
+fn http_malformed_request() {
+    // the error response must close the connection instead of being answered again
+    let response = exchange(b"GET / HTTP/1.1\r\nNot a header\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 "));
+    assert_eq!(response.matches("HTTP/1.1 ").count(), 1);
+}
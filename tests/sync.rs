@@ -0,0 +1,24 @@
+extern crate ws;
+
+mod common;
+
+use std::time::Duration;
+
+use ws::sync::Client;
+
+use common::echo_server;
+
+#[test]
+fn sync_client_echo() {
+    let (handle, addr, t) = echo_server();
+
+    let client = Client::connect(format!("ws://{}", addr)).unwrap();
+    client.send("Hello").unwrap();
+    assert_eq!(client.recv().unwrap(), ws::Message::text("Hello"));
+    assert_eq!(client.recv_timeout(Duration::from_millis(100)).unwrap(), None);
+    client.ping(vec![1, 2, 3]).unwrap();
+    client.close(ws::CloseCode::Normal).unwrap();
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
@@ -0,0 +1,150 @@
+#![cfg(any(all(not(windows), feature="ssl"), feature="rustls"))]
+extern crate ws;
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+use ws::{TlsClientConfig, TlsServerConfig};
+
+// The certificates in tests/certs are made by tests/certs/generate.sh.
+static CA: &'static str = "tests/certs/ca.pem";
+static SERVER_CERT: &'static str = "tests/certs/server.pem";
+static SERVER_KEY: &'static str = "tests/certs/server.key";
+static CLIENT_CERT: &'static str = "tests/certs/client.pem";
+static CLIENT_KEY: &'static str = "tests/certs/client.key";
+static UNTRUSTED_CERT: &'static str = "tests/certs/untrusted.pem";
+static UNTRUSTED_KEY: &'static str = "tests/certs/untrusted.key";
+
+fn server_config() -> TlsServerConfig {
+    TlsServerConfig::from_pem_files(SERVER_CERT, SERVER_KEY).unwrap()
+}
+
+fn client_config() -> TlsClientConfig {
+    let mut config = TlsClientConfig::new();
+    config.ca_file(CA);
+    config
+}
+
+// Run an echo server with TLS on a free port.
+fn wss_server(settings: Settings, tls: TlsServerConfig) -> (Sender, SocketAddr, thread::JoinHandle<()>) {
+    common::serve(Builder::new().with_settings(settings).with_tls(tls), common::Echo)
+}
+
+struct Client {
+    out: Sender,
+    events: mpsc::Sender<&'static str>,
+}
+
+impl Handler for Client {
+
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.events.send("open").unwrap();
+        self.out.send("Hello")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        assert_eq!(msg, Message::text("Hello"));
+        self.events.send("echo").unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn on_error(&mut self, _: ws::Error) {
+        self.events.send("error").unwrap();
+    }
+}
+
+// Connect to the url, send a message and return what happened until the connection ended.
+fn exchange(url: String, config: TlsClientConfig) -> Vec<&'static str> {
+    let (tx, rx) = mpsc::channel();
+    let mut client = Builder::new()
+        .with_tls_client(config)
+        .build(move |out: Sender| Client { out: out, events: tx.clone() })
+        .unwrap();
+    client.connect(url.parse().unwrap()).unwrap();
+    // a failed handshake is reported to the handler
+    let _ = client.run();
+    rx.try_iter().collect()
+}
+
+#[test]
+fn wss_echo() {
+    let (handle, addr, t) = wss_server(Settings::default(), server_config());
+    let events = exchange(format!("wss://localhost:{}", addr.port()), client_config());
+    assert_eq!(events, vec!["open", "echo"]);
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn wss_unknown_ca() {
+    let (handle, addr, t) = wss_server(Settings::default(), server_config());
+    // the client only trusts its default roots
+    let events = exchange(format!("wss://localhost:{}", addr.port()), TlsClientConfig::new());
+    assert!(!events.contains(&"open"));
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn wss_client_certificates() {
+    let mut tls = server_config();
+    tls.require_client_certs(CA).unwrap();
+    let (handle, addr, t) = wss_server(Settings::default(), tls);
+    let url = format!("wss://localhost:{}", addr.port());
+
+    let mut trusted = client_config();
+    trusted.certificate(CLIENT_CERT, CLIENT_KEY);
+    assert_eq!(exchange(url.clone(), trusted), vec!["open", "echo"]);
+
+    let mut untrusted = client_config();
+    untrusted.certificate(UNTRUSTED_CERT, UNTRUSTED_KEY);
+    assert!(!exchange(url.clone(), untrusted).contains(&"open"));
+
+    assert!(!exchange(url, client_config()).contains(&"open"));
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+fn wss_client_certificates_with_hosts() {
+    // the client asks for the added host with SNI, and must still be verified
+    let mut tls = server_config();
+    tls.require_client_certs(CA).unwrap();
+    tls.add_host("localhost", server_config()).unwrap();
+    let (handle, addr, t) = wss_server(Settings::default(), tls);
+    let url = format!("wss://localhost:{}", addr.port());
+
+    let mut trusted = client_config();
+    trusted.certificate(CLIENT_CERT, CLIENT_KEY);
+    assert_eq!(exchange(url.clone(), trusted), vec!["open", "echo"]);
+
+    let mut untrusted = client_config();
+    untrusted.certificate(UNTRUSTED_CERT, UNTRUSTED_KEY);
+    assert!(!exchange(url, untrusted).contains(&"open"));
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}
+
+#[test]
+#[cfg(unix)]
+fn ws_and_wss_on_one_port() {
+    let settings = Settings {
+        detect_tls: true,
+        .. Settings::default()
+    };
+    let (handle, addr, t) = wss_server(settings, server_config());
+
+    assert_eq!(exchange(format!("ws://{}", addr), client_config()), vec!["open", "echo"]);
+    assert_eq!(exchange(format!("wss://localhost:{}", addr.port()), client_config()), vec!["open", "echo"]);
+
+    handle.shutdown().unwrap();
+    assert!(t.join().is_ok());
+}